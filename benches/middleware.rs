@@ -0,0 +1,126 @@
+//! Measures the per-call overhead of the server middleware around a service that responds
+//! immediately.
+//!
+//! No recorder is installed so the metrics themselves are no-ops, leaving only the work done
+//! by the middleware. The number of allocations per call is printed before each benchmark.
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    convert::Infallible,
+    future::{self, Future},
+    pin::pin,
+    sync::atomic::{AtomicUsize, Ordering},
+    task::{Context, Poll, Waker},
+};
+
+use criterion::{Criterion, criterion_group, criterion_main};
+use tonic::body::Body;
+use tonic_metrics::ServerMetricsLayer;
+use tower::{Layer, Service};
+
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) }
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+/// Responds immediately with a trailers-only OK response.
+#[derive(Clone)]
+struct ReadyService;
+
+impl<B> Service<http::Request<B>> for ReadyService {
+    type Response = http::Response<Body>;
+    type Error = Infallible;
+    type Future = future::Ready<Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, _req: http::Request<B>) -> Self::Future {
+        let response = http::Response::builder()
+            .header("grpc-status", "0")
+            .body(Body::empty())
+            .unwrap();
+        future::ready(Ok(response))
+    }
+}
+
+fn request() -> http::Request<Body> {
+    http::Request::builder()
+        .version(http::Version::HTTP_2)
+        .uri("/echo.Echo/Echo")
+        .header("content-type", "application/grpc")
+        .body(Body::empty())
+        .unwrap()
+}
+
+fn call<S>(service: &mut S)
+where
+    S: Service<http::Request<Body>>,
+{
+    let mut cx = Context::from_waker(Waker::noop());
+    let future = pin!(service.call(request()));
+    assert!(future.poll(&mut cx).is_ready());
+}
+
+fn bench_service<S>(c: &mut Criterion, name: &str, mut service: S)
+where
+    S: Service<http::Request<Body>>,
+{
+    const CALLS: usize = 1_000;
+
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    for _ in 0..CALLS {
+        call(&mut service);
+    }
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed) - before;
+    println!("{name}: {} allocations per call", allocations / CALLS);
+
+    c.bench_function(name, |b| b.iter(|| call(&mut service)));
+}
+
+fn middleware(c: &mut Criterion) {
+    bench_service(c, "baseline", ReadyService);
+    bench_service(c, "default", ServerMetricsLayer::new().layer(ReadyService));
+    bench_service(
+        c,
+        "known_paths",
+        ServerMetricsLayer::builder()
+            .with_known_paths(["/echo.Echo/Echo"])
+            .build()
+            .layer(ReadyService),
+    );
+    bench_service(
+        c,
+        "no_active_requests",
+        ServerMetricsLayer::builder()
+            .with_active_requests(false)
+            .build()
+            .layer(ReadyService),
+    );
+    bench_service(
+        c,
+        "message_metrics",
+        ServerMetricsLayer::builder()
+            .with_message_sizes(true)
+            .with_messages_per_rpc(true)
+            .build()
+            .layer(ReadyService),
+    );
+}
+
+criterion_group!(benches, middleware);
+criterion_main!(benches);
@@ -0,0 +1,86 @@
+use http::{HeaderMap, HeaderName};
+
+const BAGGAGE_HEADER: HeaderName = HeaderName::from_static("baggage");
+
+/// Finds the value of `key` in the W3C `baggage` headers, percent-decoded.
+///
+/// Entries are `key=value` pairs separated by `,`, with optional `;` properties after the value
+/// that are ignored. Values that don't decode to UTF-8 are treated as missing.
+///
+/// See: https://www.w3.org/TR/baggage/#header-content
+pub(crate) fn baggage_value(headers: &HeaderMap, key: &str) -> Option<String> {
+    headers
+        .get_all(BAGGAGE_HEADER)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .find_map(|member| {
+            let (name, value) = member.split_once('=')?;
+            if name.trim() != key {
+                return None;
+            }
+            let value = value.split_once(';').map_or(value, |(value, _)| value);
+            percent_decode(value.trim())
+        })
+}
+
+fn percent_decode(value: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(value.len());
+    let mut rest = value.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        rest = tail;
+        if byte == b'%'
+            && let Some(hex) = rest.get(..2)
+            && let Some(decoded) = std::str::from_utf8(hex)
+                .ok()
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+        {
+            bytes.push(decoded);
+            rest = &rest[2..];
+        } else {
+            bytes.push(byte);
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use http::{HeaderMap, HeaderValue};
+
+    use super::baggage_value;
+
+    fn headers(values: &[&'static str]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for value in values {
+            headers.append("baggage", HeaderValue::from_static(value));
+        }
+        headers
+    }
+
+    #[test]
+    fn finds_key() {
+        let headers = headers(&["tenant=acme, region = eu-west-1 ;ttl=60"]);
+        assert_eq!(baggage_value(&headers, "tenant").as_deref(), Some("acme"));
+        assert_eq!(
+            baggage_value(&headers, "region").as_deref(),
+            Some("eu-west-1")
+        );
+        assert_eq!(baggage_value(&headers, "ttl"), None);
+        assert_eq!(baggage_value(&headers, "missing"), None);
+    }
+
+    #[test]
+    fn searches_every_header() {
+        let headers = headers(&["tenant=acme", "region=eu"]);
+        assert_eq!(baggage_value(&headers, "region").as_deref(), Some("eu"));
+    }
+
+    #[test]
+    fn decodes_values() {
+        let headers = headers(&["name=caf%C3%A9%20bar,broken=%ZZ,invalid=%FF"]);
+        assert_eq!(baggage_value(&headers, "name").as_deref(), Some("café bar"));
+        assert_eq!(baggage_value(&headers, "broken").as_deref(), Some("%ZZ"));
+        assert_eq!(baggage_value(&headers, "invalid"), None);
+    }
+}
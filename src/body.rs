@@ -0,0 +1,639 @@
+use std::{
+    borrow::Cow,
+    panic::{self, AssertUnwindSafe},
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll, ready},
+    time::Instant,
+};
+
+use bytes::Buf;
+use http::{HeaderMap, Response, StatusCode};
+use http_body::{Body, Frame, SizeHint};
+#[cfg(feature = "metrics")]
+use metrics::histogram;
+use pin_project_lite::pin_project;
+
+use crate::{
+    ActiveRequestGuard, DurationUnit, Label, LabelKeys,
+    clock::SharedClock,
+    connect::{self, ConnectResponse},
+    core::RpcMeasurement,
+    hook::{OnResponse, RpcInfo},
+    message::MessageMetrics,
+    sink::Sink,
+};
+
+pub(crate) const GRPC_STATUS_HEADER: &str = "grpc-status";
+const GRPC_STATUS_CANCELLED: i32 = 1;
+const GRPC_STATUS_INTERNAL: i32 = 13;
+/// The `error.type` of RPCs whose inner service or response body panicked.
+pub(crate) const PANIC_ERROR_TYPE: &str = "panic";
+/// The `error.type` of RPCs whose response body failed mid-stream.
+const STREAM_ERROR_TYPE: &str = "stream_error";
+/// The `error.type` of gRPC responses that ended with HTTP 200 but neither a message nor a
+/// `grpc-status`.
+const MALFORMED_RESPONSE_ERROR_TYPE: &str = "malformed_response";
+
+/// A duration measurement waiting for the final gRPC status before being recorded.
+///
+/// The clock keeps running and the request stays active until then, so streaming responses
+/// are measured until their last message.
+#[derive(Debug)]
+pub(crate) struct PendingRecord {
+    pub(crate) metric_name: Cow<'static, str>,
+    /// Additional names the duration histogram is recorded under, each in its own unit.
+    pub(crate) metric_aliases: Arc<[(Cow<'static, str>, DurationUnit)]>,
+    pub(crate) counter_name: &'static str,
+    pub(crate) labels: Vec<Label>,
+    pub(crate) start: Instant,
+    pub(crate) clock: SharedClock,
+    pub(crate) active_request: Option<ActiveRequestGuard>,
+    pub(crate) duration_unit: DurationUnit,
+    pub(crate) on_response: Option<OnResponse>,
+    pub(crate) sink: Sink,
+    pub(crate) label_keys: Arc<LabelKeys>,
+    pub(crate) result_label: bool,
+    pub(crate) http_status_label: bool,
+    /// Whether to record `rpc.grpc.status_code` by name rather than number.
+    pub(crate) status_code_names: bool,
+    /// Whether the request was gRPC, so a response without any gRPC frames is malformed.
+    pub(crate) grpc: bool,
+    /// Whether the request was Connect, which reports errors in the response body instead.
+    pub(crate) connect: bool,
+    /// Kept open until the RPC is recorded so the span covers the whole response.
+    #[cfg(feature = "tracing")]
+    pub(crate) span: tracing::Span,
+    /// Trace id from the request `TraceId` extension or `traceparent` header, exposed to the
+    /// hook and sink only.
+    #[cfg(feature = "exemplars")]
+    pub(crate) trace_id: Option<String>,
+    /// The trace id is only attached to RPCs slower than this, if set.
+    #[cfg(feature = "exemplars")]
+    pub(crate) exemplar_threshold: Option<std::time::Duration>,
+    /// Time spent building the labels, the time spent recording is added before it is recorded
+    /// on the overhead histogram.
+    #[cfg(feature = "metrics")]
+    pub(crate) overhead: Option<std::time::Duration>,
+}
+
+impl PendingRecord {
+    /// Records the histogram and request counter with the `rpc.grpc.status_code` label, also setting
+    /// `error.type` if the HTTP or gRPC status is not OK.
+    ///
+    /// When no `grpc-status` was received, the status is derived from the HTTP status.
+    pub(crate) fn record(self, grpc_status: Option<i32>, http_status: StatusCode) {
+        self.record_status(grpc_status, http_status, false);
+    }
+
+    /// Like [`Self::record`], for a response body that has ended. A gRPC response that ended
+    /// with HTTP 200 without any data or `grpc-status` is recorded as `INTERNAL` with the
+    /// `malformed_response` error type, as it would otherwise look like a success.
+    pub(crate) fn record_end(
+        self,
+        grpc_status: Option<i32>,
+        http_status: StatusCode,
+        received_data: bool,
+    ) {
+        let malformed =
+            self.grpc && grpc_status.is_none() && http_status == StatusCode::OK && !received_data;
+        self.record_status(grpc_status, http_status, malformed);
+    }
+
+    fn record_status(mut self, grpc_status: Option<i32>, http_status: StatusCode, malformed: bool) {
+        let code = if malformed {
+            GRPC_STATUS_INTERNAL
+        } else {
+            grpc_status.unwrap_or_else(|| grpc_status_from_http(http_status))
+        };
+
+        if self.http_status_label {
+            self.labels.push((
+                self.label_keys.http_response_status_code,
+                Cow::Owned(http_status.as_str().to_string()),
+            ));
+        }
+
+        self.push_status_code(code);
+
+        if malformed {
+            self.labels.push((
+                self.label_keys.error_type,
+                Cow::Borrowed(MALFORMED_RESPONSE_ERROR_TYPE),
+            ));
+        } else if http_status.is_client_error() || http_status.is_server_error() {
+            // Just the code, the reason phrase only adds noise to the label
+            self.labels.push((
+                self.label_keys.error_type,
+                Cow::Owned(http_status.as_str().to_string()),
+            ));
+        } else if code != 0 {
+            self.labels
+                .push((self.label_keys.error_type, grpc_error_type(code)));
+        }
+
+        self.emit(Some(code));
+    }
+
+    /// Records the histogram and request counter for a Connect RPC, with the Connect error code
+    /// as `error.type` and its gRPC equivalent as `rpc.grpc.status_code`.
+    pub(crate) fn record_connect(
+        mut self,
+        error_code: Option<&'static str>,
+        http_status: StatusCode,
+    ) {
+        let code = error_code.map_or(0, connect::grpc_code);
+
+        if self.http_status_label {
+            self.labels.push((
+                self.label_keys.http_response_status_code,
+                Cow::Owned(http_status.as_str().to_string()),
+            ));
+        }
+
+        self.push_status_code(code);
+
+        if let Some(error_code) = error_code {
+            self.labels
+                .push((self.label_keys.error_type, Cow::Borrowed(error_code)));
+        }
+
+        self.emit(Some(code));
+    }
+
+    /// Records the histogram and request counter for an RPC where the inner service
+    /// failed without producing a response.
+    pub(crate) fn record_error(mut self, error_type: Cow<'static, str>) {
+        self.labels.push((self.label_keys.error_type, error_type));
+        self.emit(None);
+    }
+
+    /// Records the histogram and request counter for an RPC whose response future was dropped
+    /// before completing, with the `CANCELLED` status.
+    pub(crate) fn record_cancelled(mut self) {
+        self.push_status_code(GRPC_STATUS_CANCELLED);
+        self.labels
+            .push((self.label_keys.error_type, Cow::Borrowed("cancelled")));
+        self.emit(Some(GRPC_STATUS_CANCELLED));
+    }
+
+    fn push_status_code(&mut self, code: i32) {
+        let value = match self.status_code_names.then(|| grpc_status_name(code)) {
+            Some(Some(name)) => Cow::Borrowed(name),
+            _ => Cow::Owned(code.to_string()),
+        };
+        self.labels
+            .push((self.label_keys.rpc_grpc_status_code, value));
+    }
+
+    fn emit(mut self, grpc_status: Option<i32>) {
+        #[cfg(feature = "metrics")]
+        let emit_start = self.overhead.map(|overhead| (overhead, Instant::now()));
+        self.active_request = None;
+
+        if self.result_label {
+            let is_error = self
+                .labels
+                .iter()
+                .any(|(key, _)| *key == self.label_keys.error_type);
+            self.labels.push((
+                self.label_keys.result,
+                Cow::Borrowed(if is_error { "error" } else { "ok" }),
+            ));
+        }
+
+        let elapsed = self.clock.0.now().duration_since(self.start);
+
+        #[cfg(feature = "tracing")]
+        self.span
+            .record("duration_ms", elapsed.as_secs_f64() * 1000.0);
+
+        #[cfg(feature = "tracing")]
+        for (key, value) in &self.labels {
+            if *key == self.label_keys.rpc_grpc_status_code {
+                self.span.record("rpc.grpc.status_code", value.as_ref());
+            } else if *key == self.label_keys.error_type {
+                self.span.record("error.type", value.as_ref());
+            }
+        }
+
+        #[cfg(feature = "exemplars")]
+        let trace_id = self.trace_id.as_deref().filter(|_| {
+            self.exemplar_threshold
+                .is_none_or(|threshold| elapsed > threshold)
+        });
+
+        if let Some(on_response) = &self.on_response {
+            (on_response.0)(&RpcInfo {
+                labels: &self.labels,
+                keys: &self.label_keys,
+                grpc_status,
+                duration: elapsed,
+                #[cfg(feature = "exemplars")]
+                trace_id,
+            });
+        }
+
+        self.sink.0.record_measurement(&RpcMeasurement {
+            labels: &self.labels,
+            grpc_status,
+            duration: elapsed,
+            #[cfg(feature = "exemplars")]
+            trace_id,
+        });
+
+        self.sink.0.record_duration(
+            &self.metric_name,
+            &self.labels,
+            self.duration_unit.convert(elapsed),
+        );
+        for (name, unit) in &*self.metric_aliases {
+            self.sink
+                .0
+                .record_duration(name, &self.labels, unit.convert(elapsed));
+        }
+        self.sink
+            .0
+            .increment_requests(&Cow::Borrowed(self.counter_name), &self.labels);
+
+        #[cfg(feature = "metrics")]
+        if let Some((overhead, emit_start)) = emit_start {
+            histogram!(crate::TONIC_METRICS_OVERHEAD)
+                .record((overhead + emit_start.elapsed()).as_secs_f64());
+        }
+    }
+}
+
+/// Parses the `grpc-status` value out of a header or trailer map.
+pub(crate) fn grpc_status(headers: &HeaderMap) -> Option<i32> {
+    headers
+        .get(GRPC_STATUS_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse().ok())
+}
+
+/// Names the gRPC status code for the `error.type` label, such as `deadline_exceeded`, so alerts
+/// don't need to know the numeric codes. Unknown codes are kept numeric.
+pub(crate) fn grpc_error_type(code: i32) -> Cow<'static, str> {
+    Cow::Borrowed(match code {
+        1 => "cancelled",
+        2 => "unknown",
+        3 => "invalid_argument",
+        4 => "deadline_exceeded",
+        5 => "not_found",
+        6 => "already_exists",
+        7 => "permission_denied",
+        8 => "resource_exhausted",
+        9 => "failed_precondition",
+        10 => "aborted",
+        11 => "out_of_range",
+        12 => "unimplemented",
+        13 => "internal",
+        14 => "unavailable",
+        15 => "data_loss",
+        16 => "unauthenticated",
+        _ => return Cow::Owned(code.to_string()),
+    })
+}
+
+/// Returns the canonical name of a gRPC status code, such as `NOT_FOUND`.
+///
+/// See: https://grpc.github.io/grpc/core/md_doc_statuscodes.html
+fn grpc_status_name(code: i32) -> Option<&'static str> {
+    Some(match code {
+        0 => "OK",
+        1 => "CANCELLED",
+        2 => "UNKNOWN",
+        3 => "INVALID_ARGUMENT",
+        4 => "DEADLINE_EXCEEDED",
+        5 => "NOT_FOUND",
+        6 => "ALREADY_EXISTS",
+        7 => "PERMISSION_DENIED",
+        8 => "RESOURCE_EXHAUSTED",
+        9 => "FAILED_PRECONDITION",
+        10 => "ABORTED",
+        11 => "OUT_OF_RANGE",
+        12 => "UNIMPLEMENTED",
+        13 => "INTERNAL",
+        14 => "UNAVAILABLE",
+        15 => "DATA_LOSS",
+        16 => "UNAUTHENTICATED",
+        _ => return None,
+    })
+}
+
+/// Maps an HTTP status to a gRPC status code for responses without a `grpc-status`.
+///
+/// See: https://github.com/grpc/grpc/blob/master/doc/http-grpc-status-mapping.md
+fn grpc_status_from_http(status: StatusCode) -> i32 {
+    match status {
+        StatusCode::OK => 0,
+        StatusCode::BAD_REQUEST => 13,
+        StatusCode::UNAUTHORIZED => 16,
+        StatusCode::FORBIDDEN => 7,
+        StatusCode::NOT_FOUND => 12,
+        StatusCode::TOO_MANY_REQUESTS
+        | StatusCode::BAD_GATEWAY
+        | StatusCode::SERVICE_UNAVAILABLE
+        | StatusCode::GATEWAY_TIMEOUT => 14,
+        _ => 2,
+    }
+}
+
+pin_project! {
+    /// Response body that records the RPC metrics once the gRPC status is known.
+    ///
+    /// gRPC almost always responds with HTTP 200 and sends the real status in the
+    /// `grpc-status` trailer, so the metric is recorded when the trailers are received.
+    /// If the stream ends without trailers, the status is derived from the HTTP status.
+    /// If the body is dropped before it ended, e.g. when the client disconnects mid-stream,
+    /// the RPC is recorded as cancelled. If the inner body fails it is recorded with the
+    /// `stream_error` error type, and if it panics with the `panic` error type. A gRPC
+    /// response ending with HTTP 200 but neither a message nor a `grpc-status` is recorded
+    /// as `INTERNAL` with the `malformed_response` error type.
+    #[derive(Debug)]
+    pub struct ResponseBody<B> {
+        #[pin]
+        inner: B,
+        pending: Option<PendingRecord>,
+        http_status: StatusCode,
+        messages: Option<MessageMetrics>,
+        // Whether any data has been received, to tell empty responses apart
+        received_data: bool,
+        // Whether the inner body reported its end, so dropping it isn't a cancellation
+        ended: bool,
+        connect: Option<ConnectResponse>,
+    }
+
+    impl<B> PinnedDrop for ResponseBody<B> {
+        fn drop(this: Pin<&mut Self>) {
+            let this = this.project();
+            if let Some(pending) = this.pending.take() {
+                // A unary Connect response is a single message carrying the error, so it can be
+                // recorded even if the body wasn't reported as ended
+                let connect_unary = this
+                    .connect
+                    .as_ref()
+                    .is_some_and(|connect| !connect.is_streaming() && *this.received_data);
+                if *this.ended || connect_unary {
+                    record_end_of_stream(
+                        pending,
+                        this.connect.take(),
+                        *this.http_status,
+                        *this.received_data,
+                    );
+                } else {
+                    pending.record_cancelled();
+                }
+            }
+            if let Some(messages) = this.messages.take() {
+                messages.finish();
+            }
+        }
+    }
+}
+
+impl<B: Body> ResponseBody<B> {
+    /// Wraps the response body, recording right away for trailers-only responses
+    /// which carry the gRPC status in the headers, and for empty bodies.
+    pub(crate) fn wrap(
+        response: Response<B>,
+        pending: PendingRecord,
+        messages: Option<MessageMetrics>,
+    ) -> Response<Self> {
+        let (parts, inner) = response.into_parts();
+
+        let http_status = parts.status;
+
+        // Connect responses never carry a `grpc-status`, their status is only known at the end
+        let mut connect = pending
+            .connect
+            .then(|| ConnectResponse::new(&parts.headers));
+
+        let ended = inner.is_end_stream();
+
+        let pending = match grpc_status(&parts.headers).filter(|_| connect.is_none()) {
+            Some(status) => {
+                pending.record(Some(status), http_status);
+                None
+            }
+            // hyper sends an empty body along with the headers and never polls it, so the
+            // response is recorded now, e.g. as malformed if it is a gRPC response
+            None if ended => {
+                record_end_of_stream(pending, connect.take(), http_status, false);
+                None
+            }
+            None => Some(pending),
+        };
+
+        Response::from_parts(
+            parts,
+            Self {
+                ended,
+                inner,
+                pending,
+                http_status,
+                messages,
+                received_data: false,
+                connect,
+            },
+        )
+    }
+}
+
+impl<B> ResponseBody<B> {
+    /// Wraps the response body without recording any metrics.
+    pub(crate) fn untracked(response: Response<B>) -> Response<Self> {
+        let http_status = response.status();
+        response.map(|inner| Self {
+            inner,
+            pending: None,
+            http_status,
+            messages: None,
+            received_data: false,
+            ended: false,
+            connect: None,
+        })
+    }
+}
+
+impl<B: Body> Body for ResponseBody<B> {
+    type Data = B::Data;
+    type Error = B::Error;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let this = self.project();
+        let mut inner = this.inner;
+        let frame = match panic::catch_unwind(AssertUnwindSafe(|| inner.as_mut().poll_frame(cx))) {
+            Ok(poll) => ready!(poll),
+            Err(payload) => {
+                if let Some(messages) = this.messages.take() {
+                    messages.finish();
+                }
+                if let Some(pending) = this.pending.take() {
+                    pending.record_error(Cow::Borrowed(PANIC_ERROR_TYPE));
+                }
+                panic::resume_unwind(payload);
+            }
+        };
+
+        match &frame {
+            Some(Ok(frame)) => {
+                if let Some(data) = frame.data_ref() {
+                    // hyper stops polling once the body reports its end, without waiting for `None`
+                    *this.ended = inner.is_end_stream();
+                    *this.received_data |= data.has_remaining();
+                    if let Some(messages) = this.messages {
+                        messages.observe(data);
+                    }
+                    if let Some(connect) = this.connect {
+                        connect.observe(data);
+                    }
+
+                    if *this.ended {
+                        if let Some(messages) = this.messages.take() {
+                            messages.finish();
+                        }
+                        if let Some(pending) = this.pending.take() {
+                            record_end_of_stream(
+                                pending,
+                                this.connect.take(),
+                                *this.http_status,
+                                *this.received_data,
+                            );
+                        }
+                    }
+                }
+
+                if let Some(trailers) = frame.trailers_ref() {
+                    if let Some(messages) = this.messages.take() {
+                        messages.finish();
+                    }
+                    if let Some(pending) = this.pending.take() {
+                        match this.connect.take() {
+                            Some(connect) => pending.record_connect(
+                                connect.error_code(*this.http_status),
+                                *this.http_status,
+                            ),
+                            None => pending.record_end(
+                                grpc_status(trailers),
+                                *this.http_status,
+                                *this.received_data,
+                            ),
+                        }
+                    }
+                }
+            }
+            Some(Err(_)) => {
+                if let Some(messages) = this.messages.take() {
+                    messages.finish();
+                }
+                if let Some(pending) = this.pending.take() {
+                    pending.record_error(Cow::Borrowed(STREAM_ERROR_TYPE));
+                }
+            }
+            None => {
+                if let Some(messages) = this.messages.take() {
+                    messages.finish();
+                }
+                if let Some(pending) = this.pending.take() {
+                    record_end_of_stream(
+                        pending,
+                        this.connect.take(),
+                        *this.http_status,
+                        *this.received_data,
+                    );
+                }
+            }
+        }
+
+        Poll::Ready(frame)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+/// Records an RPC whose response body ended without trailers.
+fn record_end_of_stream(
+    pending: PendingRecord,
+    connect: Option<ConnectResponse>,
+    http_status: StatusCode,
+    received_data: bool,
+) {
+    match connect {
+        Some(connect) => pending.record_connect(connect.error_code(http_status), http_status),
+        None => pending.record_end(None, http_status, received_data),
+    }
+}
+
+pin_project! {
+    /// Request body that records metrics about the gRPC messages it contains.
+    #[derive(Debug)]
+    pub struct RequestBody<B> {
+        #[pin]
+        inner: B,
+        messages: Option<MessageMetrics>,
+    }
+
+    impl<B> PinnedDrop for RequestBody<B> {
+        fn drop(this: Pin<&mut Self>) {
+            if let Some(messages) = this.project().messages.take() {
+                messages.finish();
+            }
+        }
+    }
+}
+
+impl<B> RequestBody<B> {
+    pub(crate) fn new(inner: B, messages: Option<MessageMetrics>) -> Self {
+        Self { inner, messages }
+    }
+}
+
+impl<B: Body> Body for RequestBody<B> {
+    type Data = B::Data;
+    type Error = B::Error;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let this = self.project();
+        let frame = ready!(this.inner.poll_frame(cx));
+
+        match &frame {
+            Some(Ok(frame)) => {
+                if let Some(data) = frame.data_ref()
+                    && let Some(messages) = this.messages
+                {
+                    messages.observe(data);
+                }
+            }
+            Some(Err(_)) => {}
+            None => {
+                if let Some(messages) = this.messages.take() {
+                    messages.finish();
+                }
+            }
+        }
+
+        Poll::Ready(frame)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
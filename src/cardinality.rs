@@ -0,0 +1,105 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{PoisonError, RwLock},
+};
+
+/// Label value used for service/method pairs seen after the limit was reached.
+const OTHER: &str = "other";
+
+/// Caps the number of distinct `rpc.service`/`rpc.method` pairs, shared by every clone of the
+/// middleware.
+///
+/// Pairs that were seen before, and every pair once the limit is reached, only take the read
+/// lock, so the write lock is limited to the first RPC of at most `max` pairs.
+#[derive(Debug)]
+pub(crate) struct MethodLimiter {
+    max: usize,
+    seen: RwLock<Seen>,
+}
+
+#[derive(Debug, Default)]
+struct Seen {
+    methods: HashMap<String, HashSet<String>>,
+    len: usize,
+}
+
+impl MethodLimiter {
+    pub(crate) fn new(max: usize) -> Self {
+        Self {
+            max,
+            seen: RwLock::default(),
+        }
+    }
+
+    /// Returns the service and method to label the RPC with, replacing both with `other`
+    /// once the limit is reached for pairs that haven't been seen before.
+    pub(crate) fn check<'a>(&self, service: &'a str, method: &'a str) -> (&'a str, &'a str) {
+        if let Some(labels) = self
+            .seen
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .lookup(self.max, service, method)
+        {
+            return labels;
+        }
+
+        // Another RPC may have inserted the pair or reached the limit since the read lock was
+        // released, so check again
+        let mut seen = self.seen.write().unwrap_or_else(PoisonError::into_inner);
+        if let Some(labels) = seen.lookup(self.max, service, method) {
+            return labels;
+        }
+
+        seen.len += 1;
+        seen.methods
+            .entry(service.to_string())
+            .or_default()
+            .insert(method.to_string());
+        (service, method)
+    }
+}
+
+impl Seen {
+    /// Returns the labels of a pair that was seen before or can't be inserted anymore, `None`
+    /// when the pair has to be inserted.
+    fn lookup<'a>(
+        &self,
+        max: usize,
+        service: &'a str,
+        method: &'a str,
+    ) -> Option<(&'a str, &'a str)> {
+        if self
+            .methods
+            .get(service)
+            .is_some_and(|methods| methods.contains(method))
+        {
+            return Some((service, method));
+        }
+
+        (self.len >= max).then_some((OTHER, OTHER))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MethodLimiter;
+
+    #[test]
+    fn buckets_pairs_over_the_limit() {
+        let limiter = MethodLimiter::new(2);
+
+        assert_eq!(limiter.check("a.A", "One"), ("a.A", "One"));
+        assert_eq!(limiter.check("a.A", "Two"), ("a.A", "Two"));
+        assert_eq!(limiter.check("a.A", "Three"), ("other", "other"));
+        assert_eq!(limiter.check("b.B", "One"), ("other", "other"));
+    }
+
+    #[test]
+    fn keeps_pairs_seen_before_the_limit() {
+        let limiter = MethodLimiter::new(1);
+
+        assert_eq!(limiter.check("a.A", "One"), ("a.A", "One"));
+        assert_eq!(limiter.check("a.A", "Two"), ("other", "other"));
+        assert_eq!(limiter.check("a.A", "One"), ("a.A", "One"));
+    }
+}
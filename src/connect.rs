@@ -0,0 +1,297 @@
+use bytes::Buf;
+use http::{HeaderMap, Request, StatusCode};
+
+const CONNECT_PROTOCOL_VERSION_HEADER: &str = "connect-protocol-version";
+const CONNECT_STREAMING_CONTENT_TYPE: &str = "application/connect+";
+/// Envelope flag marking the end-of-stream message of a streaming Connect response.
+const END_STREAM_FLAG: u8 = 0b10;
+/// Length of the envelope prefix: a 1 byte flags field followed by a 4 byte big-endian length.
+const ENVELOPE_HEADER_LEN: usize = 5;
+/// Only the end of a response is kept to find its error, which Connect errors easily fit into.
+const MAX_TAIL_LEN: usize = 4096;
+
+/// The Connect error codes, in the order of their numeric gRPC equivalents starting at `1`.
+///
+/// See: https://connectrpc.com/docs/protocol/#error-codes
+const CODES: [&str; 16] = [
+    "canceled",
+    "unknown",
+    "invalid_argument",
+    "deadline_exceeded",
+    "not_found",
+    "already_exists",
+    "permission_denied",
+    "resource_exhausted",
+    "failed_precondition",
+    "aborted",
+    "out_of_range",
+    "unimplemented",
+    "internal",
+    "unavailable",
+    "data_loss",
+    "unauthenticated",
+];
+
+/// Whether the request uses the Connect protocol, detected from the streaming `content-type`,
+/// the `connect-protocol-version` header unary requests send, or the `connect` query parameter
+/// of unary GET requests.
+pub(crate) fn is_connect<T>(req: &Request<T>) -> bool {
+    is_streaming(req.headers())
+        || req.headers().contains_key(CONNECT_PROTOCOL_VERSION_HEADER)
+        || req
+            .uri()
+            .query()
+            .is_some_and(|query| query.split('&').any(|param| param == "connect=v1"))
+}
+
+/// Whether the request is a unary Connect request, whose body is the bare message rather than
+/// a stream of enveloped messages.
+#[cfg(feature = "metrics")]
+pub(crate) fn is_unary_connect<T>(req: &Request<T>) -> bool {
+    is_connect(req) && !is_streaming(req.headers())
+}
+
+fn is_streaming(headers: &HeaderMap) -> bool {
+    headers
+        .get(http::header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|content_type| content_type.starts_with(CONNECT_STREAMING_CONTENT_TYPE))
+}
+
+/// The numeric gRPC status code equivalent to a Connect error code.
+pub(crate) fn grpc_code(code: &str) -> i32 {
+    CODES
+        .iter()
+        .position(|c| *c == code)
+        .map_or(2, |i| i as i32 + 1)
+}
+
+/// Collects the end of a Connect response body to find the error it ended with.
+///
+/// Unary responses carry the error as the JSON body of a non-200 response, while streaming
+/// responses always use HTTP 200 and end with an end-of-stream message holding the error.
+#[derive(Debug)]
+pub(crate) struct ConnectResponse {
+    streaming: bool,
+    tail: Vec<u8>,
+}
+
+impl ConnectResponse {
+    pub(crate) fn new(headers: &HeaderMap) -> Self {
+        Self {
+            streaming: is_streaming(headers),
+            tail: Vec::new(),
+        }
+    }
+
+    /// Whether the response is a streaming response, which ends with an end-of-stream message.
+    pub(crate) fn is_streaming(&self) -> bool {
+        self.streaming
+    }
+
+    pub(crate) fn observe(&mut self, data: &impl Buf) {
+        let mut data = data.chunk();
+        // Only a contiguous chunk is available here, which is the whole frame for the bodies
+        // produced by hyper and tonic
+        if data.len() >= MAX_TAIL_LEN {
+            data = &data[data.len() - MAX_TAIL_LEN..];
+            self.tail.clear();
+        }
+        let overflow = (self.tail.len() + data.len()).saturating_sub(MAX_TAIL_LEN);
+        self.tail.drain(..overflow);
+        self.tail.extend_from_slice(data);
+    }
+
+    /// The Connect error code the response ended with, `None` if it succeeded.
+    pub(crate) fn error_code(&self, http_status: StatusCode) -> Option<&'static str> {
+        if !self.streaming {
+            if http_status.is_success() {
+                return None;
+            }
+            return Some(error_code(&self.tail).unwrap_or_else(|| code_from_http(http_status)));
+        }
+
+        if !http_status.is_success() {
+            return Some(code_from_http(http_status));
+        }
+
+        match end_stream_message(&self.tail) {
+            Some((flags, message)) if flags & 1 == 0 => {
+                let error = find_key(message, "error")?;
+                Some(error_code(error).unwrap_or("unknown"))
+            }
+            // Compressed end-of-stream messages can't be inspected
+            Some(_) => Some("unknown"),
+            // Streams must end with an end-of-stream message
+            None => Some("internal"),
+        }
+    }
+}
+
+/// Finds the end-of-stream message at the end of `tail`, returning its flags and contents.
+fn end_stream_message(tail: &[u8]) -> Option<(u8, &[u8])> {
+    (0..tail.len().saturating_sub(ENVELOPE_HEADER_LEN - 1)).find_map(|start| {
+        let header = &tail[start..start + ENVELOPE_HEADER_LEN];
+        let len = u32::from_be_bytes([header[1], header[2], header[3], header[4]]) as usize;
+        let message = &tail[start + ENVELOPE_HEADER_LEN..];
+        let is_end_stream = header[0] & END_STREAM_FLAG != 0 && header[0] & !0b11 == 0;
+        (is_end_stream && message.len() == len).then_some((header[0], message))
+    })
+}
+
+/// Extracts the `code` of a JSON Connect error, limited to the codes defined by Connect to
+/// bound the label values.
+fn error_code(json: &[u8]) -> Option<&'static str> {
+    let value = find_key(json, "code")?;
+    let value = value.strip_prefix(b"\"")?;
+    let end = value.iter().position(|b| *b == b'"')?;
+    let code = &value[..end];
+    Some(
+        CODES
+            .iter()
+            .find(|c| c.as_bytes() == code)
+            .copied()
+            .unwrap_or("unknown"),
+    )
+}
+
+/// Returns the JSON following `"key":`, without parsing the document.
+fn find_key<'a>(json: &'a [u8], key: &str) -> Option<&'a [u8]> {
+    let quoted = format!("\"{key}\"");
+    let start = json
+        .windows(quoted.len())
+        .position(|window| window == quoted.as_bytes())?;
+    let rest = json[start + quoted.len()..].trim_ascii_start();
+    Some(rest.strip_prefix(b":")?.trim_ascii_start())
+}
+
+/// Maps an HTTP status to a Connect code for errors without a Connect error body.
+///
+/// See: https://connectrpc.com/docs/protocol/#http-to-error-code
+fn code_from_http(status: StatusCode) -> &'static str {
+    match status {
+        StatusCode::BAD_REQUEST => "internal",
+        StatusCode::UNAUTHORIZED => "unauthenticated",
+        StatusCode::FORBIDDEN => "permission_denied",
+        StatusCode::NOT_FOUND => "unimplemented",
+        StatusCode::TOO_MANY_REQUESTS
+        | StatusCode::BAD_GATEWAY
+        | StatusCode::SERVICE_UNAVAILABLE
+        | StatusCode::GATEWAY_TIMEOUT => "unavailable",
+        _ => "unknown",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use http::{HeaderMap, HeaderValue, Request, StatusCode};
+
+    use super::{ConnectResponse, grpc_code, is_connect};
+
+    fn response(content_type: &'static str, chunks: &[&[u8]]) -> ConnectResponse {
+        let mut headers = HeaderMap::new();
+        headers.insert("content-type", HeaderValue::from_static(content_type));
+        let mut response = ConnectResponse::new(&headers);
+        for chunk in chunks {
+            response.observe(&Bytes::copy_from_slice(chunk));
+        }
+        response
+    }
+
+    fn envelope(flags: u8, message: &[u8]) -> Vec<u8> {
+        let mut envelope = vec![flags];
+        envelope.extend_from_slice(&(message.len() as u32).to_be_bytes());
+        envelope.extend_from_slice(message);
+        envelope
+    }
+
+    #[test]
+    fn detects_connect_requests() {
+        let request = |builder: http::request::Builder| builder.body(()).unwrap();
+        assert!(is_connect(&request(
+            Request::builder().header("content-type", "application/connect+proto")
+        )));
+        assert!(is_connect(&request(
+            Request::builder()
+                .header("content-type", "application/json")
+                .header("connect-protocol-version", "1")
+        )));
+        assert!(is_connect(&request(
+            Request::builder().uri("/echo.Echo/Echo?connect=v1&encoding=json")
+        )));
+        assert!(!is_connect(&request(
+            Request::builder().header("content-type", "application/grpc")
+        )));
+        assert!(!is_connect(&request(
+            Request::builder().header("content-type", "application/json")
+        )));
+    }
+
+    #[test]
+    fn maps_codes_to_grpc() {
+        assert_eq!(grpc_code("canceled"), 1);
+        assert_eq!(grpc_code("not_found"), 5);
+        assert_eq!(grpc_code("unauthenticated"), 16);
+        assert_eq!(grpc_code("bogus"), 2);
+    }
+
+    #[test]
+    fn unary_errors() {
+        let ok = response("application/json", &[br#"{"message":"hi"}"#]);
+        assert_eq!(ok.error_code(StatusCode::OK), None);
+
+        let error = response(
+            "application/json",
+            &[
+                br#"{"code": "not_found", "#,
+                br#""message":"no such echo"}"#,
+            ],
+        );
+        assert_eq!(error.error_code(StatusCode::NOT_FOUND), Some("not_found"));
+
+        let unknown = response("application/json", &[br#"{"code":"made_up"}"#]);
+        assert_eq!(unknown.error_code(StatusCode::BAD_REQUEST), Some("unknown"));
+
+        // Not a Connect error, e.g. from a proxy
+        let proxy = response("text/html", &[b"<html>Bad Gateway</html>"]);
+        assert_eq!(
+            proxy.error_code(StatusCode::BAD_GATEWAY),
+            Some("unavailable")
+        );
+    }
+
+    #[test]
+    fn streaming_errors() {
+        let message = envelope(0, b"\x0a\x02hi");
+
+        let ok = response(
+            "application/connect+proto",
+            &[&message, &envelope(2, b"{}")],
+        );
+        assert_eq!(ok.error_code(StatusCode::OK), None);
+
+        let end = envelope(
+            2,
+            br#"{"error":{"code":"resource_exhausted","message":"slow down"}}"#,
+        );
+        let (first, second) = end.split_at(3);
+        let error = response("application/connect+proto", &[&message, first, second]);
+        assert_eq!(error.error_code(StatusCode::OK), Some("resource_exhausted"));
+
+        let compressed = response("application/connect+proto", &[&envelope(3, b"\x1f\x8b")]);
+        assert_eq!(compressed.error_code(StatusCode::OK), Some("unknown"));
+
+        let truncated = response("application/connect+proto", &[&message]);
+        assert_eq!(truncated.error_code(StatusCode::OK), Some("internal"));
+    }
+
+    #[test]
+    fn keeps_only_the_tail() {
+        let large = envelope(0, &[0; 10_000]);
+        let end = envelope(2, br#"{"error":{"code":"aborted"}}"#);
+        let error = response("application/connect+json", &[&large, &end]);
+        assert_eq!(error.tail.len(), 4096);
+        assert_eq!(error.error_code(StatusCode::OK), Some("aborted"));
+    }
+}
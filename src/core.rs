@@ -0,0 +1,48 @@
+//! The data model of a recorded RPC, independent of the backend it is emitted to.
+//!
+//! The middlewares derive one [`RpcMeasurement`] per completed RPC and hand it to their
+//! [`MetricsSink`](crate::MetricsSink) before recording the duration and request count. A sink
+//! that only consumes measurements through
+//! [`MetricsSink::record_measurement`](crate::MetricsSink::record_measurement) never touches the
+//! global `metrics` recorder for these metrics.
+
+use std::time::Duration;
+
+use crate::Label;
+
+/// The labels, status and duration of a completed RPC.
+///
+/// The labels are the ones the duration histogram and request counter are recorded with,
+/// including `rpc.grpc.status_code` and `error.type` once known.
+#[derive(Debug, Clone, Copy)]
+pub struct RpcMeasurement<'a> {
+    pub(crate) labels: &'a [Label],
+    pub(crate) grpc_status: Option<i32>,
+    pub(crate) duration: Duration,
+    #[cfg(feature = "exemplars")]
+    pub(crate) trace_id: Option<&'a str>,
+}
+
+impl<'a> RpcMeasurement<'a> {
+    /// The labels the RPC is recorded with.
+    pub fn labels(&self) -> &'a [Label] {
+        self.labels
+    }
+
+    /// The gRPC status code, `None` when the inner service failed without producing a response.
+    pub fn grpc_status(&self) -> Option<i32> {
+        self.grpc_status
+    }
+
+    /// The duration of the RPC, as measured by the configured [`Clock`](crate::Clock).
+    pub fn duration(&self) -> Duration {
+        self.duration
+    }
+
+    /// The trace id of the RPC, for sinks that attach it to the duration as an exemplar, see
+    /// [`TraceId`](crate::TraceId).
+    #[cfg(feature = "exemplars")]
+    pub fn trace_id(&self) -> Option<&'a str> {
+        self.trace_id
+    }
+}
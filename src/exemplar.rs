@@ -0,0 +1,110 @@
+use http::{HeaderMap, Request};
+
+const TRACEPARENT_HEADER: &str = "traceparent";
+
+/// Request extension holding the trace id of an RPC, such as the one of the active
+/// OpenTelemetry span, for layers above the middleware to insert.
+///
+/// Takes priority over the W3C `traceparent` header. The trace id is never recorded as a label,
+/// it is exposed through [`RpcInfo::trace_id`](crate::RpcInfo::trace_id) and
+/// [`RpcMeasurement::trace_id`](crate::core::RpcMeasurement::trace_id) for sinks that can
+/// attach exemplars.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct TraceId(pub String);
+
+/// The trace id of the request, from the [`TraceId`] extension or the `traceparent` header.
+pub(crate) fn request_trace_id<T>(req: &Request<T>) -> Option<String> {
+    req.extensions()
+        .get::<TraceId>()
+        .map(|TraceId(trace_id)| trace_id.clone())
+        .or_else(|| trace_id(req.headers()))
+}
+
+/// Extracts the trace id from a W3C `traceparent` header.
+///
+/// See: https://www.w3.org/TR/trace-context/#traceparent-header
+pub(crate) fn trace_id(headers: &HeaderMap) -> Option<String> {
+    let value = headers.get(TRACEPARENT_HEADER)?.to_str().ok()?;
+    let mut parts = value.trim().split('-');
+
+    let version = parts.next()?;
+    let trace_id = parts.next()?;
+    let parent_id = parts.next()?;
+    let flags = parts.next()?;
+
+    let is_hex = |s: &str, len: usize| s.len() == len && s.bytes().all(|b| b.is_ascii_hexdigit());
+
+    if !is_hex(version, 2) || version == "ff" || !is_hex(parent_id, 16) || !is_hex(flags, 2) {
+        return None;
+    }
+
+    // An all zero trace id is invalid
+    if !is_hex(trace_id, 32) || trace_id.bytes().all(|b| b == b'0') {
+        return None;
+    }
+
+    Some(trace_id.to_ascii_lowercase())
+}
+
+#[cfg(test)]
+mod tests {
+    use http::{HeaderMap, HeaderValue, Request};
+
+    use super::{TraceId, request_trace_id, trace_id};
+
+    fn headers(traceparent: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("traceparent", HeaderValue::from_static(traceparent));
+        headers
+    }
+
+    #[test]
+    fn parses_trace_id() {
+        assert_eq!(
+            trace_id(&headers(
+                "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"
+            )),
+            Some("4bf92f3577b34da6a3ce929d0e0e4736".to_string())
+        );
+    }
+
+    #[test]
+    fn prefers_extension() {
+        let request = Request::builder()
+            .header(
+                "traceparent",
+                "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            )
+            .extension(TraceId("0af7651916cd43dd8448eb211c80319c".to_string()))
+            .body(())
+            .unwrap();
+        assert_eq!(
+            request_trace_id(&request).as_deref(),
+            Some("0af7651916cd43dd8448eb211c80319c")
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_headers() {
+        assert_eq!(trace_id(&HeaderMap::new()), None);
+        assert_eq!(trace_id(&headers("garbage")), None);
+        assert_eq!(
+            trace_id(&headers(
+                "00-00000000000000000000000000000000-00f067aa0ba902b7-01"
+            )),
+            None
+        );
+        assert_eq!(
+            trace_id(&headers(
+                "ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"
+            )),
+            None
+        );
+        assert_eq!(
+            trace_id(&headers(
+                "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7"
+            )),
+            None
+        );
+    }
+}
@@ -0,0 +1,229 @@
+use std::{
+    borrow::Cow,
+    future::Future,
+    panic::{self, AssertUnwindSafe},
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll, ready},
+    time::Instant,
+};
+
+use http::Response;
+#[cfg(feature = "metrics")]
+use http::{HeaderMap, header::CONTENT_LENGTH};
+use http_body::Body;
+#[cfg(feature = "metrics")]
+use metrics::{counter, histogram};
+use pin_project_lite::pin_project;
+
+use crate::{
+    ActiveRequestGuard, DurationUnit, Label, LabelKeys,
+    body::{PANIC_ERROR_TYPE, PendingRecord, ResponseBody},
+    clock::SharedClock,
+    hook::{ErrorType, OnResponse},
+    message::MessageMetrics,
+    sink::Sink,
+};
+
+/// The state captured in `call()` needed to record the RPC once the inner future resolves.
+pub(crate) struct InFlight {
+    pub(crate) metric_name: Cow<'static, str>,
+    pub(crate) metric_aliases: Arc<[(Cow<'static, str>, DurationUnit)]>,
+    pub(crate) counter_name: &'static str,
+    pub(crate) labels: Vec<Label>,
+    pub(crate) start: Instant,
+    pub(crate) duration_unit: DurationUnit,
+    pub(crate) active_request: Option<ActiveRequestGuard>,
+    pub(crate) response_messages: Option<MessageMetrics>,
+    pub(crate) on_response: Option<OnResponse>,
+    pub(crate) sink: Sink,
+    pub(crate) clock: SharedClock,
+    pub(crate) label_keys: Arc<LabelKeys>,
+    /// Whether to add the `result` label.
+    pub(crate) result_label: bool,
+    /// Whether to add the `http.response.status_code` label.
+    pub(crate) http_status_label: bool,
+    /// Whether to record `rpc.grpc.status_code` by name.
+    pub(crate) status_code_names: bool,
+    /// Whether the request was gRPC.
+    pub(crate) grpc: bool,
+    /// Whether the request was Connect.
+    pub(crate) connect: bool,
+    /// Histogram for the response `content-length`, if enabled.
+    #[cfg(feature = "metrics")]
+    pub(crate) content_length_metric: Option<&'static str>,
+    /// Counter for errors returned by the inner service, labeled with a coarse category, if
+    /// enabled.
+    #[cfg(feature = "metrics")]
+    pub(crate) transport_errors_metric: Option<&'static str>,
+    /// Maps errors returned by the inner service to `error.type`, defaulting to the error's
+    /// type name.
+    pub(crate) error_type: Option<ErrorType>,
+    #[cfg(feature = "tracing")]
+    pub(crate) span: tracing::Span,
+    #[cfg(feature = "exemplars")]
+    pub(crate) trace_id: Option<String>,
+    #[cfg(feature = "exemplars")]
+    pub(crate) exemplar_threshold: Option<std::time::Duration>,
+    /// Time spent building the labels in `call()`, if the overhead histogram is enabled.
+    #[cfg(feature = "metrics")]
+    pub(crate) overhead: Option<std::time::Duration>,
+}
+
+impl InFlight {
+    /// Hands the RPC over to be recorded once its final status is known, which for streaming
+    /// responses is only once the body has ended.
+    fn finish(self) -> (PendingRecord, Option<MessageMetrics>) {
+        let pending = PendingRecord {
+            metric_name: self.metric_name,
+            metric_aliases: self.metric_aliases,
+            counter_name: self.counter_name,
+            labels: self.labels,
+            start: self.start,
+            clock: self.clock,
+            active_request: self.active_request,
+            duration_unit: self.duration_unit,
+            on_response: self.on_response,
+            sink: self.sink,
+            label_keys: self.label_keys,
+            result_label: self.result_label,
+            http_status_label: self.http_status_label,
+            status_code_names: self.status_code_names,
+            grpc: self.grpc,
+            connect: self.connect,
+            #[cfg(feature = "tracing")]
+            span: self.span,
+            #[cfg(feature = "exemplars")]
+            trace_id: self.trace_id,
+            #[cfg(feature = "exemplars")]
+            exemplar_threshold: self.exemplar_threshold,
+            #[cfg(feature = "metrics")]
+            overhead: self.overhead,
+        };
+
+        (pending, self.response_messages)
+    }
+}
+
+pin_project! {
+    /// Response future returned by the server and client middlewares.
+    ///
+    /// Dropping the future before it completes, e.g. when the client disconnects, records the
+    /// RPC as cancelled. If the inner future panics, the RPC is recorded with the `panic`
+    /// error type before the panic is resumed.
+    pub struct ResponseFuture<F> {
+        #[pin]
+        inner: F,
+        in_flight: Option<InFlight>,
+    }
+
+    impl<F> PinnedDrop for ResponseFuture<F> {
+        fn drop(this: Pin<&mut Self>) {
+            if let Some(in_flight) = this.project().in_flight.take() {
+                in_flight.finish().0.record_cancelled();
+            }
+        }
+    }
+}
+
+impl<F> ResponseFuture<F> {
+    pub(crate) fn new(inner: F, in_flight: InFlight) -> Self {
+        Self {
+            inner,
+            in_flight: Some(in_flight),
+        }
+    }
+
+    /// Polls the inner future without recording any metrics.
+    pub(crate) fn untracked(inner: F) -> Self {
+        Self {
+            inner,
+            in_flight: None,
+        }
+    }
+}
+
+impl<F, B, E> Future for ResponseFuture<F>
+where
+    F: Future<Output = Result<Response<B>, E>>,
+    B: Body,
+    E: 'static,
+{
+    type Output = Result<Response<ResponseBody<B>>, E>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+
+        #[cfg(feature = "tracing")]
+        let _entered = this
+            .in_flight
+            .as_ref()
+            .map(|in_flight| in_flight.span.enter());
+
+        let poll = panic::catch_unwind(AssertUnwindSafe(|| this.inner.poll(cx)));
+
+        #[cfg(feature = "tracing")]
+        drop(_entered);
+
+        let result = match poll {
+            Ok(poll) => ready!(poll),
+            Err(payload) => {
+                if let Some(in_flight) = this.in_flight.take() {
+                    in_flight
+                        .finish()
+                        .0
+                        .record_error(Cow::Borrowed(PANIC_ERROR_TYPE));
+                }
+                panic::resume_unwind(payload);
+            }
+        };
+
+        let Some(mut in_flight) = this.in_flight.take() else {
+            return Poll::Ready(result.map(ResponseBody::untracked));
+        };
+
+        #[cfg(feature = "metrics")]
+        let content_length_metric = in_flight.content_length_metric;
+        #[cfg(feature = "metrics")]
+        let transport_errors_metric = in_flight.transport_errors_metric;
+        let error_type = in_flight.error_type.take();
+        let (pending, response_messages) = in_flight.finish();
+
+        Poll::Ready(match result {
+            Ok(response) => {
+                #[cfg(feature = "metrics")]
+                if let Some(metric) = content_length_metric
+                    && let Some(len) = content_length(response.headers())
+                {
+                    histogram!(metric, &pending.labels).record(len as f64);
+                }
+                Ok(ResponseBody::wrap(response, pending, response_messages))
+            }
+            Err(err) => {
+                #[cfg(feature = "metrics")]
+                if let Some(metric) = transport_errors_metric {
+                    let mut labels = pending.labels.clone();
+                    labels.push((
+                        pending.label_keys.error_type,
+                        Cow::Borrowed(crate::client::transport_error_category(&err)),
+                    ));
+                    counter!(metric, &labels).increment(1);
+                }
+                pending.record_error(match error_type {
+                    Some(error_type) => error_type.map(&err),
+                    None => Cow::Borrowed(std::any::type_name::<E>()),
+                });
+                Err(err)
+            }
+        })
+    }
+}
+
+/// Parses the `content-length` header, if it is set to a valid length.
+#[cfg(feature = "metrics")]
+pub(crate) fn content_length(headers: &HeaderMap) -> Option<u64> {
+    headers
+        .get(CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse().ok())
+}
@@ -0,0 +1,57 @@
+use std::sync::{
+    Arc,
+    atomic::{AtomicBool, Ordering},
+};
+
+/// A runtime switch for the metrics recorded by the layers it is shared with, e.g. to stop
+/// recording during an incident without a redeploy.
+///
+/// Every layer has its own handle, enabled by default, unless one is shared between layers with
+/// [`ServerMetricsLayerBuilder::handle`](crate::ServerMetricsLayerBuilder::handle) or
+/// [`ClientMetricsLayerBuilder::handle`](crate::client::ClientMetricsLayerBuilder::handle).
+/// While disabled, RPCs are passed through without building labels or recording anything.
+///
+/// ```
+/// use tonic_metrics::{MetricsHandle, ServerMetricsLayer};
+///
+/// let handle = MetricsHandle::new();
+/// let layer = ServerMetricsLayer::builder().handle(handle.clone()).build();
+///
+/// handle.disable();
+/// assert!(!layer.handle().is_enabled());
+/// ```
+#[derive(Debug, Clone)]
+pub struct MetricsHandle(Arc<AtomicBool>);
+
+impl MetricsHandle {
+    /// Creates an enabled handle.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Whether RPCs are currently recorded.
+    pub fn is_enabled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /// Enables or disables recording for every layer sharing this handle.
+    ///
+    /// RPCs already in flight are still recorded once they complete.
+    pub fn set_enabled(&self, enabled: bool) {
+        self.0.store(enabled, Ordering::Relaxed);
+    }
+
+    pub fn enable(&self) {
+        self.set_enabled(true);
+    }
+
+    pub fn disable(&self) {
+        self.set_enabled(false);
+    }
+}
+
+impl Default for MetricsHandle {
+    fn default() -> Self {
+        Self(Arc::new(AtomicBool::new(true)))
+    }
+}
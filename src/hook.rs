@@ -0,0 +1,135 @@
+use std::{any::Any, borrow::Cow, fmt, sync::Arc, time::Duration};
+
+use crate::{Label, LabelKeys};
+
+/// Details of a completed RPC, passed to the `on_response` hook.
+///
+/// This is derived once per RPC, from the same labels the metrics are recorded with, including
+/// for RPCs where the inner service failed or the response was cancelled.
+#[derive(Debug)]
+pub struct RpcInfo<'a> {
+    pub(crate) labels: &'a [Label],
+    pub(crate) keys: &'a LabelKeys,
+    pub(crate) grpc_status: Option<i32>,
+    pub(crate) duration: Duration,
+    #[cfg(feature = "exemplars")]
+    pub(crate) trace_id: Option<&'a str>,
+}
+
+impl RpcInfo<'_> {
+    /// The `rpc.service` label.
+    pub fn service(&self) -> &str {
+        self.label(self.keys.rpc_service).unwrap_or_default()
+    }
+
+    /// The `rpc.method` label.
+    pub fn method(&self) -> &str {
+        self.label(self.keys.rpc_method).unwrap_or_default()
+    }
+
+    /// The gRPC status code, `None` when the inner service failed without producing a response.
+    pub fn grpc_status(&self) -> Option<i32> {
+        self.grpc_status
+    }
+
+    /// The `error.type` label, if the RPC failed.
+    pub fn error_type(&self) -> Option<&str> {
+        self.label(self.keys.error_type)
+    }
+
+    /// Whether the RPC failed, i.e. it has an `error.type` label.
+    pub fn is_error(&self) -> bool {
+        self.error_type().is_some()
+    }
+
+    /// The duration of the RPC.
+    pub fn duration(&self) -> Duration {
+        self.duration
+    }
+
+    /// The trace id of the RPC, to attach as an exemplar, see [`TraceId`](crate::TraceId).
+    #[cfg(feature = "exemplars")]
+    pub fn trace_id(&self) -> Option<&str> {
+        self.trace_id
+    }
+
+    /// All labels the metrics are recorded with.
+    pub fn labels(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.labels
+            .iter()
+            .map(|(key, value)| (*key, value.as_ref()))
+    }
+
+    fn label(&self, key: &str) -> Option<&str> {
+        // Search from the end so per-request labels take priority over static labels
+        self.labels
+            .iter()
+            .rev()
+            .find(|(k, _)| *k == key)
+            .map(|(_, value)| value.as_ref())
+    }
+}
+
+/// Callback invoked with every completed RPC before its metrics are recorded.
+#[derive(Clone)]
+pub(crate) struct OnResponse(pub(crate) Arc<dyn Fn(&RpcInfo<'_>) + Send + Sync>);
+
+impl fmt::Debug for OnResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("OnResponse")
+    }
+}
+
+type FilterFn = dyn Fn(&str, &str) -> bool + Send + Sync;
+
+/// Predicate deciding from the service and method whether an RPC is instrumented.
+#[derive(Clone)]
+pub(crate) struct Filter(pub(crate) Arc<FilterFn>);
+
+impl Filter {
+    pub(crate) fn matches(&self, (service, method): (&str, &str)) -> bool {
+        (self.0)(service, method)
+    }
+}
+
+impl fmt::Debug for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Filter")
+    }
+}
+
+type MethodMapperFn = dyn for<'a> Fn(&'a str) -> Cow<'a, str> + Send + Sync;
+
+/// Normalizes the method before it becomes the `rpc.method` label.
+#[derive(Clone)]
+pub(crate) struct MethodMapper(pub(crate) Arc<MethodMapperFn>);
+
+impl MethodMapper {
+    pub(crate) fn map<'a>(&self, method: &'a str) -> Cow<'a, str> {
+        (self.0)(method)
+    }
+}
+
+impl fmt::Debug for MethodMapper {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("MethodMapper")
+    }
+}
+
+type ErrorTypeFn = dyn Fn(&dyn Any) -> Cow<'static, str> + Send + Sync;
+
+/// Maps an error returned by the inner service to the `error.type` label.
+#[derive(Clone)]
+pub(crate) struct ErrorType(pub(crate) Arc<ErrorTypeFn>);
+
+impl ErrorType {
+    pub(crate) fn map(&self, err: &dyn Any) -> Cow<'static, str> {
+        (self.0)(err)
+    }
+}
+
+impl fmt::Debug for ErrorType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ErrorType")
+    }
+}
@@ -0,0 +1,54 @@
+use std::time::{Duration, Instant};
+
+use tonic::{Request, Status, service::Interceptor};
+
+use crate::record_server_rpc;
+
+/// A tonic [`Interceptor`] for servers that can't add a tower layer, such as when services are
+/// created with `with_interceptor`.
+///
+/// Interceptors only run before the handler and never see the response, so the duration can't
+/// be recorded by the interceptor itself. Instead it inserts an [`RpcStart`] into the request
+/// extensions, which the handler records once it knows the status. Only the labels of
+/// [`record_server_rpc`] are available, prefer
+/// [`ServerMetricsLayer`](crate::ServerMetricsLayer) whenever a layer can be added.
+///
+/// ```
+/// use tonic::service::Interceptor;
+/// use tonic_metrics::{MetricsInterceptor, RpcStart};
+///
+/// let mut interceptor = MetricsInterceptor;
+/// let request = interceptor.call(tonic::Request::new(()))?;
+///
+/// // In the handler, once the RPC has completed
+/// if let Some(start) = request.extensions().get::<RpcStart>() {
+///     start.record("echo.Echo", "Echo", tonic::Code::Ok);
+/// }
+/// # Ok::<(), tonic::Status>(())
+/// ```
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MetricsInterceptor;
+
+impl Interceptor for MetricsInterceptor {
+    fn call(&mut self, mut request: Request<()>) -> Result<Request<()>, Status> {
+        request.extensions_mut().insert(RpcStart(Instant::now()));
+        Ok(request)
+    }
+}
+
+/// The time an RPC passed through [`MetricsInterceptor`], inserted into the request extensions.
+#[derive(Debug, Clone, Copy)]
+pub struct RpcStart(Instant);
+
+impl RpcStart {
+    /// The time elapsed since the RPC passed through the interceptor.
+    pub fn elapsed(&self) -> Duration {
+        self.0.elapsed()
+    }
+
+    /// Records the RPC with [`record_server_rpc`], using the time elapsed since it passed
+    /// through the interceptor as its duration.
+    pub fn record(&self, service: &str, method: &str, status: tonic::Code) {
+        record_server_rpc(service, method, status, self.elapsed());
+    }
+}
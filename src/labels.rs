@@ -0,0 +1,62 @@
+/// The label keys used by the middlewares, defaulting to the OpenTelemetry semantic convention
+/// names.
+///
+/// Override individual keys to match an existing naming scheme:
+///
+/// ```
+/// use tonic_metrics::{LabelKeys, ServerMetricsLayer};
+///
+/// let layer = ServerMetricsLayer::builder()
+///     .label_keys(LabelKeys {
+///         rpc_service: "grpc_service",
+///         rpc_method: "grpc_method",
+///         ..LabelKeys::default()
+///     })
+///     .build();
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LabelKeys {
+    pub rpc_system: &'static str,
+    pub rpc_service: &'static str,
+    pub rpc_package: &'static str,
+    pub rpc_method: &'static str,
+    pub rpc_method_full: &'static str,
+    pub rpc_grpc_status_code: &'static str,
+    pub error_type: &'static str,
+    pub network_protocol_name: &'static str,
+    pub network_protocol_version: &'static str,
+    pub network_transport: &'static str,
+    pub server_address: &'static str,
+    pub server_port: &'static str,
+    pub client_address: &'static str,
+    pub client_port: &'static str,
+    pub rpc_attempt: &'static str,
+    pub result: &'static str,
+    pub http_response_status_code: &'static str,
+    pub rpc_grpc_request_encoding: &'static str,
+}
+
+impl Default for LabelKeys {
+    fn default() -> Self {
+        Self {
+            rpc_system: "rpc.system",
+            rpc_service: "rpc.service",
+            rpc_package: "rpc.package",
+            rpc_method: "rpc.method",
+            rpc_method_full: "rpc.method.full",
+            rpc_grpc_status_code: "rpc.grpc.status_code",
+            error_type: "error.type",
+            network_protocol_name: "network.protocol.name",
+            network_protocol_version: "network.protocol.version",
+            network_transport: "network.transport",
+            server_address: "server.address",
+            server_port: "server.port",
+            client_address: "client.address",
+            client_port: "client.port",
+            rpc_attempt: "rpc.attempt",
+            result: "result",
+            http_response_status_code: "http.response.status_code",
+            rpc_grpc_request_encoding: "rpc.grpc.request.encoding",
+        }
+    }
+}
@@ -1,122 +1,1775 @@
 use std::{
+    any::Any,
     borrow::Cow,
-    num::NonZeroUsize,
-    pin::Pin,
+    collections::{HashMap, HashSet},
+    sync::Arc,
     task::{Context, Poll},
-    time::Instant,
+    time::Duration,
+};
+#[cfg(feature = "metrics")]
+use std::{
+    sync::Once,
+    time::{Instant, SystemTime, UNIX_EPOCH},
 };
 
-use http::Request;
-use metrics::{Unit, describe_histogram, histogram};
-use tonic::transport::Body;
+use http::{HeaderName, Request};
+use http_body::Body;
+#[cfg(feature = "metrics")]
+use metrics::{
+    Gauge, Unit, counter, describe_counter, describe_gauge, describe_histogram, gauge, histogram,
+};
+use tonic::transport::server::TcpConnectInfo;
 use tower::{Layer, Service};
 
+use crate::{
+    baggage::baggage_value,
+    cardinality::MethodLimiter,
+    clock::SharedClock,
+    connect::is_connect,
+    future::InFlight,
+    hook::{ErrorType, Filter, MethodMapper, OnResponse},
+    path::{full_method, split_package},
+    sink::Sink,
+};
+#[cfg(feature = "metrics")]
+use crate::{
+    connect::is_unary_connect,
+    future::content_length,
+    message::{MessageMetrics, SizeMetrics},
+};
+
+mod baggage;
+mod body;
+mod cardinality;
 pub mod client;
+mod clock;
+mod connect;
+pub mod core;
+#[cfg(feature = "exemplars")]
+mod exemplar;
+mod future;
+mod handle;
+mod hook;
+#[cfg(feature = "metrics")]
+mod interceptor;
+mod labels;
+mod message;
+mod path;
+#[cfg(feature = "prometheus")]
+mod prometheus;
+#[cfg(feature = "metrics")]
+mod record;
+mod sample;
+mod sink;
 
-pub(crate) const RPC_SERVER_DURATION: &str = "rpc.server.duration";
-pub(crate) const RPC_CLIENT_DURATION: &str = "rpc.client.duration";
+pub use body::{RequestBody, ResponseBody};
+pub use clock::{Clock, SystemClock};
+#[cfg(feature = "exemplars")]
+pub use exemplar::TraceId;
+pub use future::ResponseFuture;
+pub use handle::MetricsHandle;
+pub use hook::RpcInfo;
+#[cfg(feature = "metrics")]
+pub use interceptor::{MetricsInterceptor, RpcStart};
+pub use labels::LabelKeys;
+pub use path::{parse_grpc_path, parse_grpc_path_strict};
+#[cfg(feature = "prometheus")]
+pub use prometheus::{install_prometheus_recorder, prometheus_builder};
+#[cfg(feature = "metrics")]
+pub use record::record_server_rpc;
+#[cfg(feature = "metrics")]
+pub use sink::MetricsRsSink;
+pub use sink::MetricsSink;
 
-#[derive(Debug, Clone, Default)]
-pub struct ServerMetricsLayer {}
+/// A metric label as recorded by the middlewares.
+pub type Label = (&'static str, Cow<'static, str>);
 
-impl<S> Layer<S> for ServerMetricsLayer {
-    type Service = ServerMetricsMiddleware<S>;
+/// Pre-parsed `(service, method)` of the paths registered with
+/// [`ServerMetricsLayerBuilder::with_known_paths`], with the method already mapped.
+type KnownPaths = HashMap<&'static str, (&'static str, Cow<'static, str>)>;
 
-    fn layer(&self, service: S) -> Self::Service {
+/// Methods registered with [`ServerMetricsLayerBuilder::with_method_allowlist`], by service.
+type MethodAllowlist = HashMap<String, HashSet<String>>;
+
+/// The default name of the server duration histogram, see
+/// [`ServerMetricsLayerBuilder::metric_name`].
+pub const RPC_SERVER_DURATION: &str = "rpc.server.duration";
+/// The name of the client duration histogram.
+pub const RPC_CLIENT_DURATION: &str = "rpc.client.duration";
+/// The name of the server request counter.
+pub const RPC_SERVER_REQUESTS: &str = "rpc.server.requests";
+/// The name of the client request counter.
+pub const RPC_CLIENT_REQUESTS: &str = "rpc.client.requests";
+/// The name of the client transport error counter.
+pub const RPC_CLIENT_TRANSPORT_ERRORS: &str = "rpc.client.transport_errors";
+/// The name of the client connection establishment duration histogram.
+pub const RPC_CLIENT_CONNECT_DURATION: &str = "rpc.client.connect.duration";
+/// The name of the request message size histogram.
+pub const RPC_SERVER_REQUEST_SIZE: &str = "rpc.server.request.size";
+/// The name of the response message size histogram.
+pub const RPC_SERVER_RESPONSE_SIZE: &str = "rpc.server.response.size";
+/// The name of the compressed request message size histogram.
+pub const RPC_SERVER_REQUEST_COMPRESSED_SIZE: &str = "rpc.server.request.compressed_size";
+/// The name of the compressed response message size histogram.
+pub const RPC_SERVER_RESPONSE_COMPRESSED_SIZE: &str = "rpc.server.response.compressed_size";
+/// The name of the request messages per RPC histogram.
+pub const RPC_SERVER_REQUESTS_PER_RPC: &str = "rpc.server.requests_per_rpc";
+/// The name of the response messages per RPC histogram.
+pub const RPC_SERVER_RESPONSES_PER_RPC: &str = "rpc.server.responses_per_rpc";
+/// The name of the server active requests gauge.
+pub const RPC_SERVER_ACTIVE_REQUESTS: &str = "rpc.server.active_requests";
+/// The name of the server last request timestamp gauge.
+pub const RPC_SERVER_LAST_REQUEST_TIMESTAMP: &str = "rpc.server.last_request_timestamp";
+/// The name of the server queue duration histogram.
+pub const RPC_SERVER_QUEUE_DURATION: &str = "rpc.server.queue.duration";
+/// The name of the server not ready counter.
+pub const RPC_SERVER_NOT_READY: &str = "rpc.server.not_ready";
+/// The name of the histogram of the time spent in the server middleware itself.
+pub const TONIC_METRICS_OVERHEAD: &str = "tonic_metrics.overhead";
+
+/// The default description of the server duration histogram.
+#[cfg(feature = "metrics")]
+const DEFAULT_DESCRIPTION: &str = "Measures the duration of inbound RPC";
+/// Label value used for the service and method of RPCs missing from the method allowlist.
+const UNKNOWN: &str = "unknown";
+
+const GRPC_CONTENT_TYPE: &str = "application/grpc";
+const GRPC_ENCODING_HEADER: &str = "grpc-encoding";
+const GRPC_WEB_CONTENT_TYPE: &str = "application/grpc-web";
+
+/// Latency buckets in seconds used unless overridden, the Prometheus client defaults extended
+/// with `0.075`, `0.75` and `7.5` as commonly used for gRPC.
+const DEFAULT_BUCKETS_SECONDS: [f64; 14] = [
+    0.005, 0.01, 0.025, 0.05, 0.075, 0.1, 0.25, 0.5, 0.75, 1.0, 2.5, 5.0, 7.5, 10.0,
+];
+
+/// The unit RPC durations are recorded in.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DurationUnit {
+    Seconds,
+    #[default]
+    Milliseconds,
+}
+
+impl DurationUnit {
+    /// The suffix Prometheus expects on the names of metrics in this unit, such as `_seconds`.
+    pub fn metric_suffix(self) -> &'static str {
+        match self {
+            DurationUnit::Seconds => "_seconds",
+            DurationUnit::Milliseconds => "_milliseconds",
+        }
+    }
+
+    /// Appends [`Self::metric_suffix`] to `name`, unless it already ends with it.
+    pub(crate) fn suffixed(self, name: Cow<'static, str>) -> Cow<'static, str> {
+        if name.ends_with(self.metric_suffix()) {
+            name
+        } else {
+            format!("{name}{}", self.metric_suffix()).into()
+        }
+    }
+
+    /// The default duration histogram buckets, from 5ms to 10s, expressed in this unit.
+    pub fn default_buckets(self) -> Vec<f64> {
+        match self {
+            DurationUnit::Seconds => DEFAULT_BUCKETS_SECONDS.to_vec(),
+            DurationUnit::Milliseconds => DEFAULT_BUCKETS_SECONDS
+                .iter()
+                .map(|bucket| bucket * 1000.0)
+                .collect(),
+        }
+    }
+
+    #[cfg(feature = "metrics")]
+    pub(crate) fn unit(self) -> Unit {
+        match self {
+            DurationUnit::Seconds => Unit::Seconds,
+            DurationUnit::Milliseconds => Unit::Milliseconds,
+        }
+    }
+
+    pub(crate) fn convert(self, duration: Duration) -> f64 {
+        match self {
+            DurationUnit::Seconds => duration.as_secs_f64(),
+            DurationUnit::Milliseconds => duration.as_secs_f64() * 1000.0,
+        }
+    }
+}
+
+/// Records the OpenTelemetry RPC server metrics: the `rpc.server.duration` histogram, the
+/// `rpc.server.requests` counter and the `rpc.server.active_requests` gauge.
+///
+/// Every metric is emitted from a single pass over the request, sharing the parsed labels, so
+/// there's no need to stack several layers.
+#[derive(Debug, Clone)]
+pub struct ServerMetricsLayer {
+    config: Arc<ServerConfig>,
+}
+
+/// Configuration shared by the layer and every middleware it creates, so cloning either is a
+/// reference count bump.
+#[derive(Debug)]
+struct ServerConfig {
+    /// Guards [`Self::describe`].
+    #[cfg(feature = "metrics")]
+    described: Once,
+    metric_name: Cow<'static, str>,
+    #[cfg(feature = "metrics")]
+    description: Cow<'static, str>,
+    metric_aliases: Arc<[(Cow<'static, str>, DurationUnit)]>,
+    duration_unit: DurationUnit,
+    rpc_system: Option<Cow<'static, str>>,
+    static_labels: Vec<Label>,
+    on_response: Option<OnResponse>,
+    sink: Sink,
+    clock: SharedClock,
+    handle: MetricsHandle,
+    protocol_version_label: bool,
+    protocol_name_label: bool,
+    transport_label: bool,
+    full_method_label: bool,
+    package_label: bool,
+    result_label: bool,
+    http_status_label: bool,
+    status_code_names: bool,
+    request_encoding_label: bool,
+    error_type: Option<ErrorType>,
+    excluded_paths: HashSet<String>,
+    grpc_only: bool,
+    filter: Option<Filter>,
+    method_mapper: Option<MethodMapper>,
+    header_labels: Vec<(HeaderName, &'static str)>,
+    baggage_labels: Vec<(&'static str, &'static str)>,
+    sample_rate: Option<f64>,
+    buckets: Vec<f64>,
+    #[cfg(feature = "metrics")]
+    message_sizes: bool,
+    #[cfg(feature = "metrics")]
+    messages_per_rpc: bool,
+    server_address: bool,
+    peer_labels: bool,
+    method_limiter: Option<MethodLimiter>,
+    method_allowlist: Option<MethodAllowlist>,
+    known_paths: KnownPaths,
+    label_keys: Arc<LabelKeys>,
+    #[cfg(feature = "metrics")]
+    request_content_length: bool,
+    #[cfg(feature = "metrics")]
+    response_content_length: bool,
+    #[cfg(feature = "metrics")]
+    last_request_timestamp: bool,
+    #[cfg(feature = "metrics")]
+    queue_duration: bool,
+    #[cfg(feature = "metrics")]
+    not_ready: bool,
+    #[cfg(feature = "metrics")]
+    overhead: bool,
+    #[cfg(feature = "metrics")]
+    active_requests: bool,
+    #[cfg(feature = "exemplars")]
+    exemplar_threshold: Option<Duration>,
+}
+
+impl ServerConfig {
+    /// Describes the metrics to the recorder, once per layer rather than for every connection
+    /// it is applied to.
+    #[cfg(feature = "metrics")]
+    fn describe(&self) {
         describe_histogram!(
-            RPC_SERVER_DURATION,
-            Unit::Milliseconds,
-            "Measures the duration of inbound RPC"
+            self.metric_name.clone(),
+            self.duration_unit.unit(),
+            self.description.clone()
         );
-        ServerMetricsMiddleware { inner: service }
+        for (name, unit) in &*self.metric_aliases {
+            describe_histogram!(name.clone(), unit.unit(), self.description.clone());
+        }
+        describe_counter!(
+            RPC_SERVER_REQUESTS,
+            Unit::Count,
+            "Measures the number of completed inbound RPC"
+        );
+        if self.active_requests {
+            describe_gauge!(
+                RPC_SERVER_ACTIVE_REQUESTS,
+                Unit::Count,
+                "Measures the number of concurrent inbound RPCs that are currently in-flight"
+            );
+        }
+        if self.message_sizes {
+            describe_histogram!(
+                RPC_SERVER_REQUEST_SIZE,
+                Unit::Bytes,
+                "Measures the size of RPC request messages (uncompressed)"
+            );
+            describe_histogram!(
+                RPC_SERVER_RESPONSE_SIZE,
+                Unit::Bytes,
+                "Measures the size of RPC response messages (uncompressed)"
+            );
+            describe_histogram!(
+                RPC_SERVER_REQUEST_COMPRESSED_SIZE,
+                Unit::Bytes,
+                "Measures the size of compressed RPC request messages"
+            );
+            describe_histogram!(
+                RPC_SERVER_RESPONSE_COMPRESSED_SIZE,
+                Unit::Bytes,
+                "Measures the size of compressed RPC response messages"
+            );
+        }
+        if self.request_content_length && !self.message_sizes {
+            describe_histogram!(
+                RPC_SERVER_REQUEST_SIZE,
+                Unit::Bytes,
+                "Measures the size of RPC requests with a content-length"
+            );
+        }
+        if self.response_content_length && !self.message_sizes {
+            describe_histogram!(
+                RPC_SERVER_RESPONSE_SIZE,
+                Unit::Bytes,
+                "Measures the size of RPC responses with a content-length"
+            );
+        }
+        if self.queue_duration {
+            describe_histogram!(
+                RPC_SERVER_QUEUE_DURATION,
+                self.duration_unit.unit(),
+                "Measures the time inbound RPCs waited for the service to become ready"
+            );
+        }
+        if self.overhead {
+            describe_histogram!(
+                TONIC_METRICS_OVERHEAD,
+                Unit::Seconds,
+                "Measures the time spent building labels and recording metrics for an inbound RPC"
+            );
+        }
+        if self.not_ready {
+            describe_counter!(
+                RPC_SERVER_NOT_READY,
+                Unit::Count,
+                "Measures the number of times the service wasn't ready to accept an inbound RPC"
+            );
+        }
+        if self.last_request_timestamp {
+            describe_gauge!(
+                RPC_SERVER_LAST_REQUEST_TIMESTAMP,
+                Unit::Seconds,
+                "The start time of the most recent inbound RPC, since the Unix epoch"
+            );
+        }
+        if self.messages_per_rpc {
+            describe_histogram!(
+                RPC_SERVER_REQUESTS_PER_RPC,
+                Unit::Count,
+                "Measures the number of messages received per RPC"
+            );
+            describe_histogram!(
+                RPC_SERVER_RESPONSES_PER_RPC,
+                Unit::Count,
+                "Measures the number of messages sent per RPC"
+            );
+        }
+    }
+
+    /// The most labels an RPC can be recorded with, so its label vector never reallocates.
+    fn label_capacity(&self) -> usize {
+        // rpc.system, rpc.method and rpc.service, plus rpc.grpc.status_code and error.type once
+        // the RPC completes
+        5 + self.static_labels.len()
+            + self.header_labels.len()
+            + self.baggage_labels.len()
+            + usize::from(self.full_method_label)
+            + usize::from(self.package_label)
+            + 2 * usize::from(self.server_address)
+            + 2 * usize::from(self.peer_labels)
+            + usize::from(self.protocol_version_label)
+            + usize::from(self.protocol_name_label)
+            + usize::from(self.transport_label)
+            + usize::from(self.result_label)
+            + usize::from(self.http_status_label)
+            + usize::from(self.request_encoding_label)
+    }
+}
+
+impl ServerMetricsLayer {
+    /// Creates a layer with the default configuration, use [`Self::builder`] to customize it.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn builder() -> ServerMetricsLayerBuilder {
+        ServerMetricsLayerBuilder::default()
+    }
+
+    /// The name of the duration histogram.
+    pub fn metric_name(&self) -> &str {
+        &self.config.metric_name
+    }
+
+    /// The duration histogram buckets, either configured with
+    /// [`ServerMetricsLayerBuilder::with_buckets`] or [`DurationUnit::default_buckets`].
+    pub fn buckets(&self) -> &[f64] {
+        &self.config.buckets
+    }
+
+    /// The handle to enable or disable recording at runtime.
+    pub fn handle(&self) -> &MetricsHandle {
+        &self.config.handle
+    }
+}
+
+impl Default for ServerMetricsLayer {
+    fn default() -> Self {
+        Self::builder().build()
     }
 }
 
 #[derive(Debug, Clone)]
+pub struct ServerMetricsLayerBuilder {
+    metric_name: Cow<'static, str>,
+    #[cfg(feature = "metrics")]
+    description: Cow<'static, str>,
+    metric_aliases: Vec<(Cow<'static, str>, Option<DurationUnit>)>,
+    duration_unit: DurationUnit,
+    rpc_system: Option<Cow<'static, str>>,
+    static_labels: Vec<Label>,
+    on_response: Option<OnResponse>,
+    sink: Sink,
+    clock: SharedClock,
+    handle: MetricsHandle,
+    protocol_version_label: bool,
+    protocol_name_label: bool,
+    transport_label: bool,
+    full_method_label: bool,
+    package_label: bool,
+    result_label: bool,
+    http_status_label: bool,
+    status_code_names: bool,
+    request_encoding_label: bool,
+    error_type: Option<ErrorType>,
+    excluded_paths: HashSet<String>,
+    grpc_only: bool,
+    filter: Option<Filter>,
+    method_mapper: Option<MethodMapper>,
+    header_labels: Vec<(HeaderName, &'static str)>,
+    baggage_labels: Vec<(&'static str, &'static str)>,
+    sample_rate: Option<f64>,
+    buckets: Option<Vec<f64>>,
+    #[cfg(feature = "metrics")]
+    message_sizes: bool,
+    #[cfg(feature = "metrics")]
+    messages_per_rpc: bool,
+    server_address: bool,
+    peer_labels: bool,
+    max_distinct_methods: Option<usize>,
+    method_allowlist: Option<MethodAllowlist>,
+    known_paths: Vec<&'static str>,
+    label_keys: LabelKeys,
+    #[cfg(feature = "metrics")]
+    request_content_length: bool,
+    #[cfg(feature = "metrics")]
+    response_content_length: bool,
+    #[cfg(feature = "metrics")]
+    last_request_timestamp: bool,
+    #[cfg(feature = "metrics")]
+    queue_duration: bool,
+    #[cfg(feature = "metrics")]
+    not_ready: bool,
+    #[cfg(feature = "metrics")]
+    overhead: bool,
+    #[cfg(feature = "metrics")]
+    active_requests: bool,
+    unit_suffix: bool,
+    #[cfg(feature = "exemplars")]
+    exemplar_threshold: Option<Duration>,
+}
+
+impl Default for ServerMetricsLayerBuilder {
+    fn default() -> Self {
+        Self {
+            metric_name: Cow::Borrowed(RPC_SERVER_DURATION),
+            #[cfg(feature = "metrics")]
+            description: Cow::Borrowed(DEFAULT_DESCRIPTION),
+            metric_aliases: Vec::new(),
+            duration_unit: DurationUnit::default(),
+            rpc_system: None,
+            static_labels: Vec::new(),
+            on_response: None,
+            sink: Sink::default(),
+            clock: SharedClock::default(),
+            handle: MetricsHandle::default(),
+            protocol_version_label: true,
+            protocol_name_label: true,
+            transport_label: true,
+            full_method_label: false,
+            package_label: false,
+            result_label: false,
+            http_status_label: false,
+            status_code_names: false,
+            request_encoding_label: false,
+            error_type: None,
+            label_keys: LabelKeys::default(),
+            #[cfg(feature = "metrics")]
+            request_content_length: false,
+            #[cfg(feature = "metrics")]
+            response_content_length: false,
+            #[cfg(feature = "metrics")]
+            last_request_timestamp: false,
+            #[cfg(feature = "metrics")]
+            queue_duration: false,
+            #[cfg(feature = "metrics")]
+            not_ready: false,
+            #[cfg(feature = "metrics")]
+            overhead: false,
+            #[cfg(feature = "metrics")]
+            active_requests: true,
+            unit_suffix: false,
+            #[cfg(feature = "exemplars")]
+            exemplar_threshold: None,
+            excluded_paths: HashSet::new(),
+            grpc_only: true,
+            filter: None,
+            method_mapper: None,
+            header_labels: Vec::new(),
+            baggage_labels: Vec::new(),
+            sample_rate: None,
+            buckets: None,
+            #[cfg(feature = "metrics")]
+            message_sizes: false,
+            #[cfg(feature = "metrics")]
+            messages_per_rpc: false,
+            server_address: false,
+            peer_labels: false,
+            max_distinct_methods: None,
+            method_allowlist: None,
+            known_paths: Vec::new(),
+        }
+    }
+}
+
+impl ServerMetricsLayerBuilder {
+    /// Sets the name of the duration histogram, defaults to [`RPC_SERVER_DURATION`].
+    pub fn metric_name(mut self, name: impl Into<Cow<'static, str>>) -> Self {
+        self.metric_name = name.into();
+        self
+    }
+
+    /// Sets the description of the duration histogram, surfaced by exporters such as the
+    /// Prometheus `HELP` text, defaults to "Measures the duration of inbound RPC".
+    #[cfg(feature = "metrics")]
+    pub fn metric_description(mut self, description: impl Into<Cow<'static, str>>) -> Self {
+        self.description = description.into();
+        self
+    }
+
+    /// Also records the duration histogram under `name`, e.g. the previous name while
+    /// migrating to a new one, so dashboards can move over before the old name is dropped.
+    ///
+    /// Can be called multiple times, every alias gets the same labels and samples as
+    /// the primary [`Self::metric_name`].
+    pub fn with_metric_alias(mut self, name: impl Into<Cow<'static, str>>) -> Self {
+        self.metric_aliases.push((name.into(), None));
+        self
+    }
+
+    /// Like [`Self::with_metric_alias`], but records the alias in its own unit, e.g. to keep a
+    /// legacy milliseconds histogram alongside one in seconds.
+    pub fn with_metric_alias_unit(
+        mut self,
+        name: impl Into<Cow<'static, str>>,
+        unit: DurationUnit,
+    ) -> Self {
+        self.metric_aliases.push((name.into(), Some(unit)));
+        self
+    }
+
+    /// Sets the unit durations are recorded in, defaults to milliseconds.
+    ///
+    /// The unit is only attached to the histogram's description, it isn't part of the metric
+    /// name unless [`Self::with_unit_suffix`] is enabled.
+    pub fn duration_unit(mut self, unit: DurationUnit) -> Self {
+        self.duration_unit = unit;
+        self
+    }
+
+    /// Appends the duration unit to the metric name, e.g. `rpc.server.duration_seconds`, as
+    /// Prometheus naming conventions expect. Names already ending with the suffix are kept
+    /// as-is.
+    pub fn with_unit_suffix(mut self, enabled: bool) -> Self {
+        self.unit_suffix = enabled;
+        self
+    }
+
+    /// Sets the `rpc.system` label, such as `apache_dubbo`.
+    ///
+    /// Defaults to `connect_rpc` for requests using the Connect protocol, `grpc-web` for
+    /// requests with a gRPC-Web `content-type` and `grpc` otherwise.
+    pub fn rpc_system(mut self, system: impl Into<Cow<'static, str>>) -> Self {
+        self.rpc_system = Some(system.into());
+        self
+    }
+
+    /// Sets a hook invoked with every completed RPC right before its metrics are recorded,
+    /// for emitting custom metrics or logs.
+    pub fn on_response<F>(mut self, hook: F) -> Self
+    where
+        F: Fn(&RpcInfo<'_>) + Send + Sync + 'static,
+    {
+        self.on_response = Some(OnResponse(Arc::new(hook)));
+        self
+    }
+
+    /// Sets the backend the duration histogram and request counter are recorded to, defaults
+    /// to `MetricsRsSink` with the `metrics` feature and to discarding them without it.
+    pub fn metrics_sink(mut self, sink: impl MetricsSink) -> Self {
+        self.sink = Sink(Arc::new(sink));
+        self
+    }
+
+    /// Sets the clock RPC durations are measured with, defaults to [`SystemClock`].
+    pub fn clock(mut self, clock: impl Clock) -> Self {
+        self.clock = SharedClock(Arc::new(clock));
+        self
+    }
+
+    /// Shares `handle` with this layer to enable or disable recording at runtime, see
+    /// [`MetricsHandle`].
+    pub fn handle(mut self, handle: MetricsHandle) -> Self {
+        self.handle = handle;
+        self
+    }
+
+    /// Whether to add the `network.protocol.version` label, defaults to `true`.
+    ///
+    /// Disabling it removes a label that is always `2` when every RPC runs over HTTP/2.
+    pub fn with_protocol_version_label(mut self, enabled: bool) -> Self {
+        self.protocol_version_label = enabled;
+        self
+    }
+
+    /// Whether to add the `network.protocol.name` label, defaults to `true` as required by the
+    /// semantic conventions.
+    ///
+    /// Disabling it removes a label that is always `http`.
+    pub fn with_protocol_name_label(mut self, enabled: bool) -> Self {
+        self.protocol_name_label = enabled;
+        self
+    }
+
+    /// Whether to add the `network.transport` label, defaults to `true` as required by the
+    /// semantic conventions.
+    ///
+    /// Disabling it removes a label that is always `tcp` unless serving HTTP/3.
+    pub fn with_transport_label(mut self, enabled: bool) -> Self {
+        self.transport_label = enabled;
+        self
+    }
+
+    /// Adds a combined `rpc.method.full` label such as `pkg.Service/Method`, alongside the split
+    /// `rpc.service` and `rpc.method` labels.
+    pub fn with_full_method_label(mut self, enabled: bool) -> Self {
+        self.full_method_label = enabled;
+        self
+    }
+
+    /// Splits the package off `rpc.service` into an `rpc.package` label, so `pkg.Service` is
+    /// recorded as `rpc.package="pkg"` and `rpc.service="Service"`.
+    ///
+    /// The service is split on its last dot, services without a package get no `rpc.package`
+    /// label. The `rpc.method.full` label keeps the full service name.
+    pub fn with_package_label(mut self, enabled: bool) -> Self {
+        self.package_label = enabled;
+        self
+    }
+
+    /// Adds a `result` label that is `ok` for successful RPCs and `error` otherwise, for simple
+    /// success-rate queries without matching on status codes. The detailed status labels are
+    /// still recorded.
+    pub fn with_result_label(mut self, enabled: bool) -> Self {
+        self.result_label = enabled;
+        self
+    }
+
+    /// Adds an `http.response.status_code` label with the numeric HTTP status, such as `503`
+    /// from a load balancer, which the gRPC status alone can hide. RPCs that fail without a
+    /// response omit the label.
+    pub fn with_http_status_label(mut self, enabled: bool) -> Self {
+        self.http_status_label = enabled;
+        self
+    }
+
+    /// Records `rpc.grpc.status_code` with the canonical name of the status, such as
+    /// `NOT_FOUND`, instead of its number. Codes without a name are kept numeric.
+    ///
+    /// The OpenTelemetry semantic conventions use the number, so this is off by default.
+    pub fn with_status_code_names(mut self, enabled: bool) -> Self {
+        self.status_code_names = enabled;
+        self
+    }
+
+    /// Adds an `rpc.grpc.request.encoding` label with the compression of the request messages
+    /// from the `grpc-encoding` header, such as `gzip`, or `identity` when uncompressed.
+    ///
+    /// Encodings other than `identity`, `gzip`, `deflate` and `zstd` are recorded as `other`
+    /// to bound the cardinality.
+    pub fn with_request_encoding_label(mut self, enabled: bool) -> Self {
+        self.request_encoding_label = enabled;
+        self
+    }
+
+    /// Sets how errors returned by the inner service are mapped to the `error.type` label,
+    /// defaults to the error's type name.
+    ///
+    /// The error is passed as [`Any`](std::any::Any) since the inner service's error type isn't
+    /// known to the layer, downcast it to map specific errors to low-cardinality categories:
+    ///
+    /// ```
+    /// use std::borrow::Cow;
+    ///
+    /// use tonic_metrics::ServerMetricsLayer;
+    ///
+    /// let layer = ServerMetricsLayer::builder()
+    ///     .with_default_error_type(|err| match err.downcast_ref::<std::io::Error>() {
+    ///         Some(err) => Cow::Owned(format!("{:?}", err.kind())),
+    ///         None => Cow::Borrowed("other"),
+    ///     })
+    ///     .build();
+    /// ```
+    pub fn with_default_error_type<F>(mut self, error_type: F) -> Self
+    where
+        F: Fn(&dyn Any) -> Cow<'static, str> + Send + Sync + 'static,
+    {
+        self.error_type = Some(ErrorType(Arc::new(error_type)));
+        self
+    }
+
+    /// Sets the label keys, defaults to the OpenTelemetry semantic convention names.
+    pub fn label_keys(mut self, keys: LabelKeys) -> Self {
+        self.label_keys = keys;
+        self
+    }
+
+    /// Adds fixed labels, such as `service.name`, to every metric emitted by the layer.
+    ///
+    /// These are added ahead of the per-request labels.
+    pub fn with_labels<I, V>(mut self, labels: I) -> Self
+    where
+        I: IntoIterator<Item = (&'static str, V)>,
+        V: Into<Cow<'static, str>>,
+    {
+        self.static_labels
+            .extend(labels.into_iter().map(|(key, value)| (key, value.into())));
+        self
+    }
+
+    /// Sets a function normalizing the `rpc.method` label, e.g. to collapse IDs in templated or
+    /// REST-transcoded methods into a stable value.
+    ///
+    /// The mapped method is what counts towards [`Self::with_max_distinct_methods`], while
+    /// [`Self::with_filter`] sees the method as received.
+    pub fn with_method_mapper<F>(mut self, mapper: F) -> Self
+    where
+        F: for<'a> Fn(&'a str) -> Cow<'a, str> + Send + Sync + 'static,
+    {
+        self.method_mapper = Some(MethodMapper(Arc::new(mapper)));
+        self
+    }
+
+    /// Adds the value of the `header` request header, such as `x-tenant-id`, as the `key` label.
+    ///
+    /// Can be called multiple times to map several headers. Requests without the header, or
+    /// with a value that isn't visible ASCII, omit the label.
+    ///
+    /// Every distinct header value creates new series, so this should only be used for headers
+    /// with a small, known set of values.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `header` is not a valid lowercase header name.
+    pub fn with_header_label(mut self, header: &'static str, key: &'static str) -> Self {
+        self.header_labels
+            .push((HeaderName::from_static(header), key));
+        self
+    }
+
+    /// Adds the value of the `baggage_key` entry in the W3C `baggage` request header, such as
+    /// `tenant`, as the `key` label.
+    ///
+    /// Can be called multiple times to map several entries. The value is percent-decoded,
+    /// requests without the entry omit the label. Like [`with_header_label`], this should only be
+    /// used for entries with a small, known set of values.
+    ///
+    /// [`with_header_label`]: Self::with_header_label
+    pub fn with_baggage_label(mut self, baggage_key: &'static str, key: &'static str) -> Self {
+        self.baggage_labels.push((baggage_key, key));
+        self
+    }
+
+    /// Whether to skip requests that are neither gRPC nor Connect, defaults to `true`.
+    ///
+    /// Plain HTTP requests hitting the gRPC port, such as load balancer health checks, don't
+    /// have a service and method and would otherwise pollute the RPC metrics. Disable this when
+    /// serving other protocols through the same layer.
+    pub fn with_grpc_only(mut self, enabled: bool) -> Self {
+        self.grpc_only = enabled;
+        self
+    }
+
+    /// Skips emitting metrics for requests to the given paths, such as
+    /// `/grpc.health.v1.Health/Check`.
+    ///
+    /// Paths are matched against the full request path, including both service and method.
+    pub fn with_excluded_paths<I, P>(mut self, paths: I) -> Self
+    where
+        I: IntoIterator<Item = P>,
+        P: Into<String>,
+    {
+        self.excluded_paths
+            .extend(paths.into_iter().map(|path| path.into()));
+        self
+    }
+
+    /// Sets a predicate deciding from the `rpc.service` and `rpc.method` whether an RPC is
+    /// instrumented, RPCs it returns `false` for are passed through without recording metrics.
+    ///
+    /// This is more flexible than a fixed list of paths, e.g. to only sample a fraction of a
+    /// high-volume method.
+    pub fn with_filter<F>(mut self, filter: F) -> Self
+    where
+        F: Fn(&str, &str) -> bool + Send + Sync + 'static,
+    {
+        self.filter = Some(Filter(Arc::new(filter)));
+        self
+    }
+
+    /// Only records a random fraction of RPCs, between `0.0` and `1.0`, to reduce the cost of
+    /// instrumenting high-volume services.
+    ///
+    /// Unsampled RPCs are passed through without recording any metrics, so every count is
+    /// scaled down by the rate and must be divided by it to estimate the true totals. Duration
+    /// percentiles are unaffected.
+    pub fn with_sample_rate(mut self, rate: f64) -> Self {
+        self.sample_rate = Some(rate.clamp(0.0, 1.0));
+        self
+    }
+
+    /// Registers the paths of known RPCs, such as `/echo.Echo/Echo`, so their `rpc.service` and
+    /// `rpc.method` labels borrow from the given strings instead of being allocated on every
+    /// request.
+    ///
+    /// Known paths are always recorded as-is, even when [`Self::with_max_distinct_methods`] has
+    /// been reached.
+    pub fn with_known_paths<I>(mut self, paths: I) -> Self
+    where
+        I: IntoIterator<Item = &'static str>,
+    {
+        self.known_paths.extend(paths);
+        self
+    }
+
+    /// Sets the buckets for the duration histogram, in the configured [`DurationUnit`].
+    ///
+    /// The `metrics` crate leaves bucketing to the recorder, so these must be handed to the
+    /// exporter along with the metric name, e.g. with `metrics-exporter-prometheus`:
+    ///
+    /// ```rust,ignore
+    /// let layer = ServerMetricsLayer::builder()
+    ///     .with_buckets(vec![1.0, 5.0, 10.0, 50.0, 100.0, 500.0])
+    ///     .build();
+    ///
+    /// PrometheusBuilder::new()
+    ///     .set_buckets_for_metric(
+    ///         Matcher::Full(layer.metric_name().to_string()),
+    ///         layer.buckets(),
+    ///     )?
+    ///     .install()?;
+    /// ```
+    ///
+    /// Defaults to [`DurationUnit::default_buckets`]. Without buckets set on the exporter,
+    /// `metrics-exporter-prometheus` renders the histogram as a summary.
+    pub fn with_buckets(mut self, buckets: Vec<f64>) -> Self {
+        self.buckets = Some(buckets);
+        self
+    }
+
+    /// Only exposes the trace id through [`RpcInfo::trace_id`] and
+    /// [`RpcMeasurement::trace_id`](core::RpcMeasurement::trace_id) for RPCs slower than
+    /// `threshold`, focusing exemplars on the tail and keeping their volume low.
+    ///
+    /// Faster RPCs are still recorded, just without the trace id.
+    #[cfg(feature = "exemplars")]
+    pub fn with_exemplar_threshold(mut self, threshold: Duration) -> Self {
+        self.exemplar_threshold = Some(threshold);
+        self
+    }
+
+    /// Records the size of every request and response message on the
+    /// `rpc.server.request.size` and `rpc.server.response.size` histograms.
+    ///
+    /// Streaming RPCs record one sample per message. Compressed messages are recorded with
+    /// their compressed size on the `rpc.server.request.compressed_size` and
+    /// `rpc.server.response.compressed_size` histograms instead, as their uncompressed size
+    /// isn't known without decompressing them. Unary Connect RPCs don't frame their messages
+    /// and aren't recorded.
+    #[cfg(feature = "metrics")]
+    pub fn with_message_sizes(mut self, enabled: bool) -> Self {
+        self.message_sizes = enabled;
+        self
+    }
+
+    /// Records the number of request and response messages of every RPC on the
+    /// `rpc.server.requests_per_rpc` and `rpc.server.responses_per_rpc` histograms.
+    ///
+    /// Unary RPCs record 1 for each, except unary Connect RPCs, which don't frame their
+    /// messages and aren't recorded.
+    #[cfg(feature = "metrics")]
+    pub fn with_messages_per_rpc(mut self, enabled: bool) -> Self {
+        self.messages_per_rpc = enabled;
+        self
+    }
+
+    /// Records the `content-length` of requests that set it on the
+    /// `rpc.server.request.size` histogram.
+    ///
+    /// This is cheaper than [`Self::with_message_sizes`] as the body doesn't need to be
+    /// decoded, but only covers requests with a known length, typically unary RPCs, and
+    /// includes the gRPC framing. Ignored when message sizes are enabled.
+    #[cfg(feature = "metrics")]
+    pub fn with_request_content_length(mut self, enabled: bool) -> Self {
+        self.request_content_length = enabled;
+        self
+    }
+
+    /// Records the `content-length` of responses that set it on the
+    /// `rpc.server.response.size` histogram.
+    ///
+    /// This is cheaper than [`Self::with_message_sizes`] as the body doesn't need to be
+    /// decoded, but only covers responses with a known length and includes the gRPC framing.
+    /// Ignored when message sizes are enabled.
+    #[cfg(feature = "metrics")]
+    pub fn with_response_content_length(mut self, enabled: bool) -> Self {
+        self.response_content_length = enabled;
+        self
+    }
+
+    /// Whether to record the `rpc.server.active_requests` gauge, defaults to `true`.
+    #[cfg(feature = "metrics")]
+    pub fn with_active_requests(mut self, enabled: bool) -> Self {
+        self.active_requests = enabled;
+        self
+    }
+
+    /// Records the wall-clock start time of the most recent request to every method, in seconds
+    /// since the Unix epoch, on the `rpc.server.last_request_timestamp` gauge.
+    ///
+    /// This makes endpoints that stopped receiving traffic stand out on dashboards.
+    #[cfg(feature = "metrics")]
+    pub fn with_last_request_timestamp(mut self, enabled: bool) -> Self {
+        self.last_request_timestamp = enabled;
+        self
+    }
+
+    /// Records how long each request waited for the inner service to become ready, on the
+    /// `rpc.server.queue.duration` histogram in the configured [`DurationUnit`].
+    ///
+    /// This is the time from the first `poll_ready` until the request is passed to `call`, so
+    /// it captures backpressure from a buffer or concurrency limit layered below this one,
+    /// separately from the handler time in the duration histogram. Queueing in layers above
+    /// happens before this middleware sees the request and isn't included.
+    #[cfg(feature = "metrics")]
+    pub fn with_queue_duration(mut self, enabled: bool) -> Self {
+        self.queue_duration = enabled;
+        self
+    }
+
+    /// Counts every `poll_ready` on which the inner service wasn't ready on the
+    /// `rpc.server.not_ready` counter, labeled with the static labels only as no request is
+    /// known yet.
+    ///
+    /// A service can be polled several times while waiting, so this measures how often
+    /// callers hit backpressure rather than how many requests did.
+    #[cfg(feature = "metrics")]
+    pub fn with_not_ready(mut self, enabled: bool) -> Self {
+        self.not_ready = enabled;
+        self
+    }
+
+    /// Records the time spent in the middleware itself, building the labels in `call()` and
+    /// recording the metrics once the RPC completes, on the unlabeled `tonic_metrics.overhead`
+    /// histogram in seconds.
+    ///
+    /// This helps tell whether instrumenting a service affects its latency. The time spent in
+    /// the [`Self::on_response`] hook and the [`MetricsSink`] is included.
+    #[cfg(feature = "metrics")]
+    pub fn with_overhead(mut self, enabled: bool) -> Self {
+        self.overhead = enabled;
+        self
+    }
+
+    /// Adds `server.address` and `server.port` labels with the local address the request was
+    /// received on, taken from tonic's [`TcpConnectInfo`] request extension.
+    ///
+    /// This lets server and client metrics be joined on the same labels. Servers using tonic's
+    /// TLS support only insert a `TlsConnectInfo` extension, so the labels are left out on
+    /// TLS connections.
+    pub fn with_server_address(mut self, enabled: bool) -> Self {
+        self.server_address = enabled;
+        self
+    }
+
+    /// Adds `client.address` and `client.port` labels with the remote address of the peer,
+    /// taken from tonic's [`TcpConnectInfo`] request extension.
+    ///
+    /// Like [`Self::with_server_address`], the labels are left out on connections using
+    /// tonic's TLS support, which only insert a `TlsConnectInfo` extension.
+    ///
+    /// Every client connection creates new series, so this should only be enabled with a
+    /// small, known set of clients. The recorder keeps every series it has seen, configure it
+    /// to drop idle ones, e.g. with `PrometheusBuilder::idle_timeout`, to bound its memory.
+    pub fn with_peer_labels(mut self, enabled: bool) -> Self {
+        self.peer_labels = enabled;
+        self
+    }
+
+    /// Caps the number of distinct `rpc.service`/`rpc.method` pairs recorded, protecting the
+    /// metrics backend from clients sending arbitrary paths.
+    ///
+    /// Once the limit is reached, RPCs to pairs that haven't been seen yet are recorded with
+    /// `other` as both the service and method.
+    pub fn with_max_distinct_methods(mut self, max: usize) -> Self {
+        self.max_distinct_methods = Some(max);
+        self
+    }
+
+    /// Only records the given `(service, method)` pairs, such as `("echo.Echo", "Echo")`, under
+    /// their own labels.
+    ///
+    /// RPCs to any other method are still recorded, but with `unknown` as both the service and
+    /// method, so the number of series is bounded by the allowlist regardless of the paths
+    /// clients send. Methods are matched before [`Self::with_method_mapper`] is applied, and the
+    /// allowlist takes priority over [`Self::with_known_paths`]. Can be called multiple times to
+    /// extend the allowlist.
+    pub fn with_method_allowlist<I, S, M>(mut self, methods: I) -> Self
+    where
+        I: IntoIterator<Item = (S, M)>,
+        S: Into<String>,
+        M: Into<String>,
+    {
+        let allowlist = self.method_allowlist.get_or_insert_default();
+        for (service, method) in methods {
+            allowlist
+                .entry(service.into())
+                .or_default()
+                .insert(method.into());
+        }
+        self
+    }
+
+    /// Appends `unit` to `name` if [`Self::with_unit_suffix`] is enabled.
+    fn suffixed(&self, name: Cow<'static, str>, unit: DurationUnit) -> Cow<'static, str> {
+        if self.unit_suffix {
+            unit.suffixed(name)
+        } else {
+            name
+        }
+    }
+
+    pub fn build(self) -> ServerMetricsLayer {
+        ServerMetricsLayer {
+            config: Arc::new(ServerConfig {
+                #[cfg(feature = "metrics")]
+                described: Once::new(),
+                metric_name: self.suffixed(self.metric_name.clone(), self.duration_unit),
+                metric_aliases: self
+                    .metric_aliases
+                    .iter()
+                    .map(|(name, unit)| {
+                        let unit = unit.unwrap_or(self.duration_unit);
+                        (self.suffixed(name.clone(), unit), unit)
+                    })
+                    .collect(),
+                #[cfg(feature = "metrics")]
+                description: self.description,
+                duration_unit: self.duration_unit,
+                rpc_system: self.rpc_system,
+                static_labels: self.static_labels,
+                on_response: self.on_response,
+                sink: self.sink,
+                clock: self.clock,
+                handle: self.handle,
+                protocol_version_label: self.protocol_version_label,
+                protocol_name_label: self.protocol_name_label,
+                transport_label: self.transport_label,
+                full_method_label: self.full_method_label,
+                package_label: self.package_label,
+                result_label: self.result_label,
+                http_status_label: self.http_status_label,
+                status_code_names: self.status_code_names,
+                request_encoding_label: self.request_encoding_label,
+                error_type: self.error_type,
+                label_keys: Arc::new(self.label_keys),
+                #[cfg(feature = "metrics")]
+                request_content_length: self.request_content_length,
+                #[cfg(feature = "metrics")]
+                response_content_length: self.response_content_length,
+                #[cfg(feature = "metrics")]
+                last_request_timestamp: self.last_request_timestamp,
+                #[cfg(feature = "metrics")]
+                queue_duration: self.queue_duration,
+                #[cfg(feature = "metrics")]
+                not_ready: self.not_ready,
+                #[cfg(feature = "metrics")]
+                overhead: self.overhead,
+                #[cfg(feature = "metrics")]
+                active_requests: self.active_requests,
+                #[cfg(feature = "exemplars")]
+                exemplar_threshold: self.exemplar_threshold,
+                excluded_paths: self.excluded_paths,
+                grpc_only: self.grpc_only,
+                filter: self.filter,
+                header_labels: self.header_labels,
+                baggage_labels: self.baggage_labels,
+                sample_rate: self.sample_rate,
+                buckets: self
+                    .buckets
+                    .unwrap_or_else(|| self.duration_unit.default_buckets()),
+                #[cfg(feature = "metrics")]
+                message_sizes: self.message_sizes,
+                #[cfg(feature = "metrics")]
+                messages_per_rpc: self.messages_per_rpc,
+                server_address: self.server_address,
+                peer_labels: self.peer_labels,
+                method_limiter: self.max_distinct_methods.map(MethodLimiter::new),
+                method_allowlist: self.method_allowlist,
+                known_paths: self
+                    .known_paths
+                    .into_iter()
+                    .map(|path| {
+                        let (service, method) = parse_grpc_path(path);
+                        let method = match &self.method_mapper {
+                            Some(mapper) => mapper.map(method),
+                            None => Cow::Borrowed(method),
+                        };
+                        (path, (service, method))
+                    })
+                    .collect(),
+                method_mapper: self.method_mapper,
+            }),
+        }
+    }
+}
+
+impl<S> Layer<S> for ServerMetricsLayer {
+    type Service = ServerMetricsMiddleware<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        #[cfg(feature = "metrics")]
+        self.config.described.call_once(|| self.config.describe());
+
+        ServerMetricsMiddleware {
+            inner: service,
+            config: self.config.clone(),
+            #[cfg(feature = "metrics")]
+            ready_since: None,
+        }
+    }
+}
+
+/// Middleware created by [`ServerMetricsLayer`].
+///
+/// The inner service doesn't need to be `Clone`. The clone-before-call pattern tower recommends
+/// is only needed when the response future has to own the service, whereas this middleware
+/// calls the inner service right away and only wraps the future it returns, so the service
+/// driven ready by `poll_ready` is always the one called.
+#[derive(Debug)]
 pub struct ServerMetricsMiddleware<S> {
     inner: S,
+    config: Arc<ServerConfig>,
+    /// When `poll_ready` was first called for the next request, if the queue duration is
+    /// recorded.
+    #[cfg(feature = "metrics")]
+    ready_since: Option<Instant>,
+}
+
+impl<S> ServerMetricsMiddleware<S> {
+    /// Wraps `inner` with the default configuration, the same as
+    /// `ServerMetricsLayer::new().layer(inner)`.
+    pub fn new(inner: S) -> Self {
+        ServerMetricsLayer::new().layer(inner)
+    }
+
+    /// Wraps `inner` with the configuration of `layer`, for stacks composed without
+    /// [`Layer`].
+    ///
+    /// Middlewares created from the same layer share its configuration, so building it once
+    /// and reusing it is cheaper than a layer per middleware.
+    pub fn with_layer(inner: S, layer: &ServerMetricsLayer) -> Self {
+        layer.layer(inner)
+    }
 }
 
-type BoxFuture<'a, T> = Pin<Box<dyn std::future::Future<Output = T> + Send + 'a>>;
+impl<S: Clone> Clone for ServerMetricsMiddleware<S> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            config: self.config.clone(),
+            // The clone hasn't been polled for readiness yet
+            #[cfg(feature = "metrics")]
+            ready_since: None,
+        }
+    }
+}
 
 impl<S, ReqBody, ResBody> Service<http::Request<ReqBody>> for ServerMetricsMiddleware<S>
 where
-    S: Service<http::Request<ReqBody>, Response = http::Response<ResBody>> + Clone + Send + 'static,
-    S::Future: Send + 'static,
-    ReqBody: Body + Send + 'static,
+    S: Service<http::Request<RequestBody<ReqBody>>, Response = http::Response<ResBody>>,
+    S::Error: 'static,
+    ReqBody: Body,
+    ResBody: Body,
 {
-    type Response = S::Response;
+    type Response = http::Response<ResponseBody<ResBody>>;
     type Error = S::Error;
-    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+    type Future = ResponseFuture<S::Future>;
 
     fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
-        self.inner.poll_ready(cx)
+        #[cfg(feature = "metrics")]
+        if self.config.queue_duration && self.ready_since.is_none() {
+            self.ready_since = Some(self.config.clock.0.now());
+        }
+        let poll = self.inner.poll_ready(cx);
+        #[cfg(feature = "metrics")]
+        if self.config.not_ready && poll.is_pending() && self.config.handle.is_enabled() {
+            counter!(RPC_SERVER_NOT_READY, &self.config.static_labels).increment(1);
+        }
+        poll
     }
 
     fn call(&mut self, req: http::Request<ReqBody>) -> Self::Future {
-        // See: https://docs.rs/tower/latest/tower/trait.Service.html#be-careful-when-cloning-inner-services
-        let clone = self.inner.clone();
-        let mut inner = std::mem::replace(&mut self.inner, clone);
+        #[cfg(feature = "metrics")]
+        let ready_since = self.ready_since.take();
+        let config = &*self.config;
 
-        let start = std::time::Instant::now();
         let path = req.uri().path();
+        if !config.handle.is_enabled()
+            || (config.grpc_only && !is_grpc(&req) && !is_connect(&req))
+            || config.excluded_paths.contains(path)
+            || config
+                .filter
+                .as_ref()
+                .is_some_and(|filter| !filter.matches(parse_grpc_path(path)))
+        {
+            return ResponseFuture::untracked(
+                self.inner.call(req.map(|b| RequestBody::new(b, None))),
+            );
+        }
+
+        if let Some(rate) = config.sample_rate
+            && !sample::sampled(rate)
+        {
+            return ResponseFuture::untracked(
+                self.inner.call(req.map(|b| RequestBody::new(b, None))),
+            );
+        }
+
+        #[cfg(feature = "metrics")]
+        let overhead_start = config.overhead.then(Instant::now);
+        let start = config.clock.0.now();
 
-        let service_method_separator: Option<NonZeroUsize> = match path.chars().next() {
-            Some('/') => path[1..]
-                .find('/')
-                .map(|p| NonZeroUsize::new(p + 1).unwrap()),
-            _ => None,
+        let allowed = config.method_allowlist.as_ref().is_none_or(|allowlist| {
+            let (service, method) = parse_grpc_path(path);
+            allowlist
+                .get(service)
+                .is_some_and(|methods| methods.contains(method))
+        });
+
+        let (rpc_service, rpc_method): (Cow<'static, str>, Cow<'static, str>) = if !allowed {
+            (Cow::Borrowed(UNKNOWN), Cow::Borrowed(UNKNOWN))
+        } else {
+            match config.known_paths.get(path) {
+                Some((service, method)) => (Cow::Borrowed(*service), method.clone()),
+                None => {
+                    let (service, method) = parse_grpc_path(path);
+                    let method = match &config.method_mapper {
+                        Some(mapper) => mapper.map(method),
+                        None => Cow::Borrowed(method),
+                    };
+                    let (service, method) = match &config.method_limiter {
+                        Some(limiter) => limiter.check(service, &method),
+                        None => (service, &*method),
+                    };
+                    (
+                        Cow::Owned(service.to_string()),
+                        Cow::Owned(method.to_string()),
+                    )
+                }
+            }
         };
 
-        let (rpc_service, rpc_method) = match service_method_separator {
-            Some(sep) => (
-                path[1..(sep).into()].to_string(),
-                path[usize::from(sep) + 1..].to_string(),
-            ),
-            // If unparsable, say service is empty and method is the entire path
-            None => ("".to_string(), path.to_string()),
+        let version = config
+            .protocol_version_label
+            .then(|| network_protocol_version(&req));
+
+        let full_method = config
+            .full_method_label
+            .then(|| full_method(&rpc_service, &rpc_method));
+
+        #[cfg(feature = "tracing")]
+        let span = tracing::info_span!(
+            "rpc.server",
+            rpc.service = rpc_service.as_ref(),
+            rpc.method = rpc_method.as_ref(),
+            rpc.grpc.status_code = tracing::field::Empty,
+            "error.type" = tracing::field::Empty,
+            duration_ms = tracing::field::Empty,
+        );
+
+        let (rpc_package, service_label): (Option<Cow<'static, str>>, _) = if config.package_label {
+            let (package, service) = split_package(&rpc_service);
+            (
+                (!package.is_empty()).then(|| Cow::Owned(package.to_string())),
+                Cow::Owned(service.to_string()),
+            )
+        } else {
+            (None, rpc_service)
+        };
+
+        // Only copied for the per-method gauges when they're enabled
+        #[cfg(feature = "metrics")]
+        let active_request = if config.active_requests || config.last_request_timestamp {
+            let mut method_labels = Vec::with_capacity(config.static_labels.len() + 3);
+            method_labels.extend_from_slice(&config.static_labels);
+            method_labels.push((config.label_keys.rpc_method, rpc_method.clone()));
+            method_labels.push((config.label_keys.rpc_service, service_label.clone()));
+            if let Some(package) = &rpc_package {
+                method_labels.push((config.label_keys.rpc_package, package.clone()));
+            }
+
+            if config.last_request_timestamp {
+                let now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default();
+                gauge!(RPC_SERVER_LAST_REQUEST_TIMESTAMP, &method_labels).set(now.as_secs_f64());
+            }
+
+            config
+                .active_requests
+                .then(|| ActiveRequestGuard::new(&method_labels))
+        } else {
+            None
         };
+        #[cfg(not(feature = "metrics"))]
+        let active_request = None;
 
-        let version = network_protocol_version(&req);
+        let mut labels = Vec::with_capacity(config.label_capacity());
+        labels.extend_from_slice(&config.static_labels);
+        let keys = &*config.label_keys;
+        labels.push((
+            keys.rpc_system,
+            rpc_system(config.rpc_system.as_ref(), &req),
+        ));
+        if config.protocol_name_label {
+            labels.push((keys.network_protocol_name, Cow::Borrowed("http")));
+        }
+        if config.transport_label {
+            labels.push((
+                keys.network_transport,
+                Cow::Borrowed(network_transport(&req)),
+            ));
+        }
+        labels.push((keys.rpc_method, rpc_method));
+        labels.push((keys.rpc_service, service_label));
+        if let Some(package) = rpc_package {
+            labels.push((keys.rpc_package, package));
+        }
+        if let Some(full_method) = full_method {
+            labels.push((keys.rpc_method_full, Cow::Owned(full_method)));
+        }
 
-        Box::pin(async move {
-            let response = inner.call(req).await?;
+        let connect_info = req.extensions().get::<TcpConnectInfo>();
 
-            let duration = Instant::now().duration_since(start);
-            let duration_millis = duration.as_millis() as f64;
+        if config.server_address
+            && let Some(local_addr) = connect_info.and_then(TcpConnectInfo::local_addr)
+        {
+            labels.push((keys.server_address, Cow::Owned(local_addr.ip().to_string())));
+            labels.push((keys.server_port, Cow::Owned(local_addr.port().to_string())));
+        }
 
-            let mut labels = Vec::with_capacity(7);
-            labels.push(("rpc.system", Cow::Borrowed("grpc")));
-            labels.push(("network.protocol.name", Cow::Borrowed("http")));
-            // TODO: If grpc eventually adds support for HTTP 3 this will be wrong :)
-            labels.push(("network.transport", Cow::Borrowed("tcp")));
-            labels.push(("rpc.method", Cow::Owned(rpc_method)));
-            labels.push(("rpc.service", Cow::Owned(rpc_service)));
+        if config.peer_labels
+            && let Some(remote_addr) = connect_info.and_then(TcpConnectInfo::remote_addr)
+        {
+            labels.push((
+                keys.client_address,
+                Cow::Owned(remote_addr.ip().to_string()),
+            ));
+            labels.push((keys.client_port, Cow::Owned(remote_addr.port().to_string())));
+        }
 
-            if let Some(version) = version {
-                labels.push(("network.protocol.version", Cow::Borrowed(version)));
+        for (header, key) in &config.header_labels {
+            if let Some(value) = req.headers().get(header).and_then(|v| v.to_str().ok()) {
+                labels.push((*key, Cow::Owned(value.to_string())));
             }
+        }
 
-            if response.status().is_client_error() || response.status().is_server_error() {
-                labels.push(("error.type", Cow::Owned(response.status().to_string())));
+        for (baggage_key, key) in &config.baggage_labels {
+            if let Some(value) = baggage_value(req.headers(), baggage_key) {
+                labels.push((*key, Cow::Owned(value)));
             }
+        }
+
+        if config.request_encoding_label {
+            labels.push((
+                keys.rpc_grpc_request_encoding,
+                Cow::Borrowed(request_encoding(&req)),
+            ));
+        }
+
+        if let Some(version) = version {
+            labels.push((keys.network_protocol_version, version));
+        }
+
+        #[cfg(feature = "metrics")]
+        if let Some(ready_since) = ready_since {
+            histogram!(RPC_SERVER_QUEUE_DURATION, &labels).record(
+                config
+                    .duration_unit
+                    .convert(start.duration_since(ready_since)),
+            );
+        }
+
+        #[cfg(feature = "metrics")]
+        if config.request_content_length
+            && !config.message_sizes
+            && let Some(len) = content_length(req.headers())
+        {
+            histogram!(RPC_SERVER_REQUEST_SIZE, &labels).record(len as f64);
+        }
+
+        // Unary Connect bodies are the bare message rather than length-prefixed frames
+        #[cfg(feature = "metrics")]
+        let (request_messages, response_messages) = {
+            let framed = !is_unary_connect(&req);
+            (
+                MessageMetrics::new(
+                    (framed && config.message_sizes).then_some(SizeMetrics {
+                        uncompressed: RPC_SERVER_REQUEST_SIZE,
+                        compressed: RPC_SERVER_REQUEST_COMPRESSED_SIZE,
+                    }),
+                    (framed && config.messages_per_rpc).then_some(RPC_SERVER_REQUESTS_PER_RPC),
+                    &labels,
+                ),
+                MessageMetrics::new(
+                    (framed && config.message_sizes).then_some(SizeMetrics {
+                        uncompressed: RPC_SERVER_RESPONSE_SIZE,
+                        compressed: RPC_SERVER_RESPONSE_COMPRESSED_SIZE,
+                    }),
+                    (framed && config.messages_per_rpc).then_some(RPC_SERVER_RESPONSES_PER_RPC),
+                    &labels,
+                ),
+            )
+        };
+        #[cfg(not(feature = "metrics"))]
+        let (request_messages, response_messages) = (None, None);
+
+        let in_flight = InFlight {
+            metric_name: config.metric_name.clone(),
+            metric_aliases: config.metric_aliases.clone(),
+            counter_name: RPC_SERVER_REQUESTS,
+            labels,
+            start,
+            duration_unit: config.duration_unit,
+            active_request,
+            response_messages,
+            on_response: config.on_response.clone(),
+            sink: config.sink.clone(),
+            clock: config.clock.clone(),
+            label_keys: config.label_keys.clone(),
+            result_label: config.result_label,
+            http_status_label: config.http_status_label,
+            status_code_names: config.status_code_names,
+            grpc: is_grpc(&req),
+            connect: is_connect(&req),
+            error_type: config.error_type.clone(),
+            #[cfg(feature = "metrics")]
+            content_length_metric: (config.response_content_length && !config.message_sizes)
+                .then_some(RPC_SERVER_RESPONSE_SIZE),
+            #[cfg(feature = "metrics")]
+            transport_errors_metric: None,
+            #[cfg(feature = "tracing")]
+            span,
+            #[cfg(feature = "exemplars")]
+            trace_id: crate::exemplar::request_trace_id(&req),
+            #[cfg(feature = "exemplars")]
+            exemplar_threshold: config.exemplar_threshold,
+            #[cfg(feature = "metrics")]
+            overhead: overhead_start.map(|start| start.elapsed()),
+        };
+
+        let req = req.map(|body| RequestBody::new(body, request_messages));
+        ResponseFuture::new(self.inner.call(req), in_flight)
+    }
+}
 
-            histogram!(RPC_SERVER_DURATION, &labels).record(duration_millis);
+/// Tracks an in-flight request, decrementing the gauge when dropped so that
+/// errors, panics and cancellations are accounted for.
+#[cfg(feature = "metrics")]
+#[derive(Debug)]
+pub(crate) struct ActiveRequestGuard {
+    gauge: Gauge,
+}
+
+/// Without the `metrics` feature there is no gauge to track, so no guard is ever created.
+#[cfg(not(feature = "metrics"))]
+#[derive(Debug)]
+pub(crate) enum ActiveRequestGuard {}
+
+#[cfg(feature = "metrics")]
+impl ActiveRequestGuard {
+    /// Starts tracking a request, labeled with the static labels and the service and method.
+    fn new(labels: &[Label]) -> Self {
+        let gauge = gauge!(RPC_SERVER_ACTIVE_REQUESTS, labels);
+        gauge.increment(1.0);
+        Self { gauge }
+    }
+}
 
-            Ok(response)
-        })
+#[cfg(feature = "metrics")]
+impl Drop for ActiveRequestGuard {
+    fn drop(&mut self) {
+        self.gauge.decrement(1.0);
     }
 }
 
-fn network_protocol_version<T>(req: &Request<T>) -> Option<&'static str> {
-    let version = req.version();
+/// Returns the configured `rpc.system`, otherwise detects Connect, see [`is_connect`], and
+/// gRPC-Web from the `content-type`, which is `application/grpc-web` optionally followed by
+/// `+proto`, `-text` or similar.
+pub(crate) fn rpc_system<T>(
+    configured: Option<&Cow<'static, str>>,
+    req: &Request<T>,
+) -> Cow<'static, str> {
+    if let Some(system) = configured {
+        return system.clone();
+    }
+
+    if is_connect(req) {
+        return Cow::Borrowed("connect_rpc");
+    }
+
+    let is_grpc_web = req
+        .headers()
+        .get(http::header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|content_type| content_type.starts_with(GRPC_WEB_CONTENT_TYPE));
+
+    Cow::Borrowed(if is_grpc_web { "grpc-web" } else { "grpc" })
+}
 
-    Some(match version {
+/// Whether the `content-type` is gRPC or gRPC-Web, which both start with `application/grpc`.
+pub(crate) fn is_grpc<T>(req: &Request<T>) -> bool {
+    req.headers()
+        .get(http::header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|content_type| content_type.starts_with(GRPC_CONTENT_TYPE))
+}
+
+/// Returns the compression of the request messages from the `grpc-encoding` header, limited to
+/// the encodings defined by gRPC.
+pub(crate) fn request_encoding<T>(req: &Request<T>) -> &'static str {
+    match req
+        .headers()
+        .get(GRPC_ENCODING_HEADER)
+        .map(|v| v.as_bytes())
+    {
+        None | Some(b"identity") => "identity",
+        Some(b"gzip") => "gzip",
+        Some(b"deflate") => "deflate",
+        Some(b"zstd") => "zstd",
+        Some(_) => "other",
+    }
+}
+
+/// HTTP/3 runs over QUIC, all prior versions run over TCP.
+pub(crate) fn network_transport<T>(req: &Request<T>) -> &'static str {
+    match req.version() {
+        http::Version::HTTP_3 => "udp",
+        _ => "tcp",
+    }
+}
+
+/// Returns the short form of the HTTP version, such as `2`, falling back to its `Debug`
+/// representation for versions without one so the label is never dropped.
+pub(crate) fn network_protocol_version<T>(req: &Request<T>) -> Cow<'static, str> {
+    Cow::Borrowed(match req.version() {
         http::Version::HTTP_09 => "0.9",
         http::Version::HTTP_10 => "1.0",
         http::Version::HTTP_11 => "1.1",
         http::Version::HTTP_2 => "2",
         http::Version::HTTP_3 => "3",
-        _ => return None,
+        version => return Cow::Owned(format!("{version:?}")),
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+
+    use http::Request;
+    use tower::Layer;
+
+    use super::{
+        DurationUnit, ServerMetricsLayer, is_grpc, network_protocol_version, request_encoding,
+        rpc_system,
+    };
+
+    fn request(content_type: &str) -> Request<()> {
+        Request::builder()
+            .header(http::header::CONTENT_TYPE, content_type)
+            .body(())
+            .unwrap()
+    }
+
+    #[test]
+    fn detects_grpc() {
+        assert_eq!(rpc_system(None, &request("application/grpc")), "grpc");
+        assert_eq!(rpc_system(None, &request("application/grpc+proto")), "grpc");
+        assert_eq!(rpc_system(None, &Request::new(())), "grpc");
+    }
+
+    #[test]
+    fn requires_grpc_content_type() {
+        assert!(is_grpc(&request("application/grpc")));
+        assert!(is_grpc(&request("application/grpc-web-text")));
+        assert!(!is_grpc(&request("text/plain")));
+        assert!(!is_grpc(&Request::new(())));
+    }
+
+    #[test]
+    fn limits_request_encodings() {
+        let request = |encoding: &str| {
+            Request::builder()
+                .header("grpc-encoding", encoding)
+                .body(())
+                .unwrap()
+        };
+        assert_eq!(request_encoding(&Request::new(())), "identity");
+        assert_eq!(request_encoding(&request("gzip")), "gzip");
+        assert_eq!(request_encoding(&request("zstd")), "zstd");
+        assert_eq!(request_encoding(&request("snappy")), "other");
+    }
+
+    #[test]
+    fn detects_connect() {
+        assert_eq!(
+            rpc_system(None, &request("application/connect+proto")),
+            "connect_rpc"
+        );
+        let unary = Request::builder()
+            .header(http::header::CONTENT_TYPE, "application/json")
+            .header("connect-protocol-version", "1")
+            .body(())
+            .unwrap();
+        assert_eq!(rpc_system(None, &unary), "connect_rpc");
+    }
+
+    #[test]
+    fn detects_grpc_web() {
+        assert_eq!(
+            rpc_system(None, &request("application/grpc-web")),
+            "grpc-web"
+        );
+        assert_eq!(
+            rpc_system(None, &request("application/grpc-web+proto")),
+            "grpc-web"
+        );
+        assert_eq!(
+            rpc_system(None, &request("application/grpc-web-text")),
+            "grpc-web"
+        );
+    }
+
+    #[test]
+    fn prefers_configured_system() {
+        let configured = Cow::Borrowed("connect_rpc");
+        assert_eq!(
+            rpc_system(Some(&configured), &request("application/grpc-web")),
+            "connect_rpc"
+        );
+    }
+
+    #[test]
+    fn middleware_shares_layer_config() {
+        let layer = ServerMetricsLayer::builder()
+            .with_excluded_paths(["/grpc.health.v1.Health/Check"])
+            .build();
+        let middleware = layer.layer(());
+        let clone = middleware.clone();
+        assert!(std::sync::Arc::ptr_eq(&layer.config, &middleware.config));
+        assert!(std::sync::Arc::ptr_eq(&middleware.config, &clone.config));
+    }
+
+    #[cfg(feature = "metrics")]
+    #[test]
+    fn describes_metrics_on_first_layer() {
+        let layer = ServerMetricsLayer::new();
+        assert!(!layer.config.described.is_completed());
+        layer.layer(());
+        assert!(layer.config.described.is_completed());
+    }
+
+    #[test]
+    fn shortens_protocol_versions() {
+        // Every version the http crate can construct has a short form, so the `Debug` fallback
+        // is only reachable if a future release adds one
+        for (version, expected) in [
+            (http::Version::HTTP_09, "0.9"),
+            (http::Version::HTTP_10, "1.0"),
+            (http::Version::HTTP_11, "1.1"),
+            (http::Version::HTTP_2, "2"),
+            (http::Version::HTTP_3, "3"),
+        ] {
+            let req = Request::builder().version(version).body(()).unwrap();
+            let label = network_protocol_version(&req);
+            assert_eq!(label, expected);
+            assert!(matches!(label, Cow::Borrowed(_)), "{version:?}");
+        }
+    }
+
+    #[test]
+    fn defaults_protocol_version_to_http_1_1() {
+        assert_eq!(network_protocol_version(&Request::new(())), "1.1");
+    }
+
+    #[test]
+    fn appends_unit_suffix() {
+        let layer = ServerMetricsLayer::builder()
+            .duration_unit(DurationUnit::Seconds)
+            .with_unit_suffix(true)
+            .build();
+        assert_eq!(layer.metric_name(), "rpc.server.duration_seconds");
+
+        let layer = ServerMetricsLayer::builder()
+            .metric_name("grpc_server_handling_seconds")
+            .duration_unit(DurationUnit::Seconds)
+            .with_unit_suffix(true)
+            .build();
+        assert_eq!(layer.metric_name(), "grpc_server_handling_seconds");
+    }
+
+    #[test]
+    fn defaults_buckets_to_the_duration_unit() {
+        let layer = ServerMetricsLayer::new();
+        assert_eq!(layer.buckets().first(), Some(&5.0));
+        assert_eq!(layer.buckets().last(), Some(&10_000.0));
+
+        let layer = ServerMetricsLayer::builder()
+            .duration_unit(DurationUnit::Seconds)
+            .build();
+        assert_eq!(layer.buckets().first(), Some(&0.005));
+
+        let layer = ServerMetricsLayer::builder()
+            .with_buckets(vec![1.0, 10.0])
+            .build();
+        assert_eq!(layer.buckets(), [1.0, 10.0]);
+    }
+}
@@ -0,0 +1,229 @@
+// The decoder is only driven by the histograms of `MessageMetrics`
+#![cfg_attr(not(feature = "metrics"), allow(dead_code))]
+
+#[cfg(feature = "metrics")]
+use std::io::IoSlice;
+
+use bytes::Buf;
+#[cfg(feature = "metrics")]
+use metrics::{Histogram, histogram};
+
+#[cfg(feature = "metrics")]
+use crate::Label;
+
+/// Length of the gRPC message prefix: a 1 byte compression flag followed by a
+/// 4 byte big-endian message length.
+const FRAME_HEADER_LEN: usize = 5;
+/// Flag bit of messages compressed with the negotiated encoding.
+const COMPRESSED_FLAG: u8 = 0b1;
+/// Flag bits of frames that don't carry a message: gRPC-Web trailers (`0x80`) and the Connect
+/// end-of-stream message (`0x02`).
+const NON_MESSAGE_FLAGS: u8 = 0x80 | 0b10;
+
+/// Incrementally decodes gRPC length-prefixed messages from a stream of data chunks.
+///
+/// Message prefixes can be split across chunks, so partial prefixes are buffered
+/// until complete. Message contents are skipped without being copied.
+#[derive(Debug, Default)]
+pub(crate) struct FrameDecoder {
+    header: [u8; FRAME_HEADER_LEN],
+    header_len: usize,
+    remaining: usize,
+}
+
+impl FrameDecoder {
+    /// Feeds a chunk of data into the decoder, calling `on_message` with the length and
+    /// compression flag of every message prefix completed by this chunk.
+    ///
+    /// Frames that don't carry a message, such as gRPC-Web trailers, are skipped.
+    pub(crate) fn decode(&mut self, mut data: &[u8], mut on_message: impl FnMut(usize, bool)) {
+        while !data.is_empty() {
+            if self.remaining > 0 {
+                let skip = self.remaining.min(data.len());
+                self.remaining -= skip;
+                data = &data[skip..];
+                continue;
+            }
+
+            let take = (FRAME_HEADER_LEN - self.header_len).min(data.len());
+            self.header[self.header_len..self.header_len + take].copy_from_slice(&data[..take]);
+            self.header_len += take;
+            data = &data[take..];
+
+            if self.header_len == FRAME_HEADER_LEN {
+                let len = u32::from_be_bytes([
+                    self.header[1],
+                    self.header[2],
+                    self.header[3],
+                    self.header[4],
+                ]) as usize;
+                self.header_len = 0;
+                self.remaining = len;
+                let flags = self.header[0];
+                if flags & NON_MESSAGE_FLAGS == 0 {
+                    on_message(len, flags & COMPRESSED_FLAG != 0);
+                }
+            }
+        }
+    }
+}
+
+/// The histograms for the size of messages, split by whether they are compressed.
+#[cfg(feature = "metrics")]
+pub(crate) struct SizeMetrics {
+    pub(crate) uncompressed: &'static str,
+    pub(crate) compressed: &'static str,
+}
+
+/// Records the size and count of gRPC messages passing through a body.
+#[cfg(feature = "metrics")]
+#[derive(Debug)]
+pub(crate) struct MessageMetrics {
+    decoder: FrameDecoder,
+    sizes: Option<(Histogram, Histogram)>,
+    count: Option<(Histogram, u64)>,
+}
+
+#[cfg(feature = "metrics")]
+impl MessageMetrics {
+    /// Returns `None` when neither metric is enabled so the body can skip decoding.
+    pub(crate) fn new(
+        size_metrics: Option<SizeMetrics>,
+        count_metric: Option<&'static str>,
+        labels: &[Label],
+    ) -> Option<Self> {
+        if size_metrics.is_none() && count_metric.is_none() {
+            return None;
+        }
+
+        Some(Self {
+            decoder: FrameDecoder::default(),
+            sizes: size_metrics.map(|names| {
+                (
+                    histogram!(names.uncompressed, labels),
+                    histogram!(names.compressed, labels),
+                )
+            }),
+            count: count_metric.map(|name| (histogram!(name, labels), 0)),
+        })
+    }
+
+    pub(crate) fn observe(&mut self, data: &impl Buf) {
+        // Bodies from hyper and tonic use a single contiguous chunk, but walk
+        // every chunk in case other body implementations use more.
+        let mut chunks = [IoSlice::new(&[]); 64];
+        let chunk_count = data.chunks_vectored(&mut chunks);
+
+        let sizes = &self.sizes;
+        let count = &mut self.count;
+        for chunk in &chunks[..chunk_count] {
+            self.decoder.decode(chunk, |len, compressed| {
+                // The uncompressed size of compressed messages isn't known without decompressing
+                // them, so only their size on the wire is recorded
+                if let Some((uncompressed_sizes, compressed_sizes)) = sizes {
+                    if compressed {
+                        compressed_sizes.record(len as f64);
+                    } else {
+                        uncompressed_sizes.record(len as f64);
+                    }
+                }
+                if let Some((_, count)) = count {
+                    *count += 1;
+                }
+            });
+        }
+    }
+
+    /// Records the number of messages seen, called once the stream has ended.
+    pub(crate) fn finish(self) {
+        if let Some((histogram, count)) = self.count {
+            histogram.record(count as f64);
+        }
+    }
+}
+
+/// Message metrics are only recorded through the `metrics` crate, so without it bodies never
+/// decode their messages.
+#[cfg(not(feature = "metrics"))]
+#[derive(Debug)]
+pub(crate) enum MessageMetrics {}
+
+#[cfg(not(feature = "metrics"))]
+impl MessageMetrics {
+    pub(crate) fn observe(&mut self, _data: &impl Buf) {
+        match *self {}
+    }
+
+    pub(crate) fn finish(self) {
+        match self {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode_all(chunks: &[&[u8]]) -> Vec<usize> {
+        decode_with_flags(chunks)
+            .into_iter()
+            .map(|(len, _)| len)
+            .collect()
+    }
+
+    fn decode_with_flags(chunks: &[&[u8]]) -> Vec<(usize, bool)> {
+        let mut decoder = FrameDecoder::default();
+        let mut messages = Vec::new();
+        for chunk in chunks {
+            decoder.decode(chunk, |len, compressed| messages.push((len, compressed)));
+        }
+        messages
+    }
+
+    #[test]
+    fn decodes_single_message() {
+        assert_eq!(decode_all(&[&[0, 0, 0, 0, 3, 1, 2, 3]]), vec![3]);
+    }
+
+    #[test]
+    fn decodes_multiple_messages_in_one_chunk() {
+        assert_eq!(
+            decode_all(&[&[0, 0, 0, 0, 1, 9, 0, 0, 0, 0, 2, 9, 9]]),
+            vec![1, 2]
+        );
+    }
+
+    #[test]
+    fn decodes_header_split_across_chunks() {
+        assert_eq!(decode_all(&[&[0, 0], &[0, 0, 2, 9], &[9]]), vec![2]);
+    }
+
+    #[test]
+    fn decodes_empty_messages() {
+        assert_eq!(decode_all(&[&[0, 0, 0, 0, 0, 0, 0, 0, 0, 0]]), vec![0, 0]);
+    }
+
+    #[test]
+    fn decodes_compression_flag() {
+        assert_eq!(
+            decode_with_flags(&[&[1, 0, 0, 0, 1, 9, 0, 0, 0, 0, 1, 9]]),
+            vec![(1, true), (1, false)]
+        );
+    }
+
+    #[test]
+    fn skips_grpc_web_trailers() {
+        assert_eq!(
+            decode_with_flags(&[&[0, 0, 0, 0, 1, 9, 0x80, 0, 0, 0, 2, 9, 9]]),
+            vec![(1, false)]
+        );
+    }
+
+    #[test]
+    fn skips_connect_end_of_stream() {
+        // Also when the end-of-stream message is compressed
+        assert_eq!(
+            decode_with_flags(&[&[1, 0, 0, 0, 1, 9, 0x02, 0, 0, 0, 2, 9, 9, 0x03, 0, 0, 0, 0]]),
+            vec![(1, true)]
+        );
+    }
+}
@@ -0,0 +1,182 @@
+/// Splits a gRPC request path such as `/pkg.Service/Method` into its service and method.
+///
+/// The method ends at the next `/`, anything after it is ignored to prevent unexpected
+/// paths from creating new label values. If the path can't be parsed, the service is
+/// empty and the method is the entire path. Use [`parse_grpc_path_strict`] to reject
+/// such paths instead.
+///
+/// ```
+/// assert_eq!(
+///     tonic_metrics::parse_grpc_path("/echo.Echo/Echo"),
+///     ("echo.Echo", "Echo")
+/// );
+/// assert_eq!(
+///     tonic_metrics::parse_grpc_path("/echo.Echo/Echo/extra"),
+///     ("echo.Echo", "Echo")
+/// );
+/// assert_eq!(tonic_metrics::parse_grpc_path("/health"), ("", "/health"));
+/// ```
+pub fn parse_grpc_path(path: &str) -> (&str, &str) {
+    path.strip_prefix('/')
+        .and_then(|rest| rest.split_once('/'))
+        .map(|(service, rest)| {
+            (
+                service,
+                rest.split_once('/').map_or(rest, |(method, _)| method),
+            )
+        })
+        .unwrap_or(("", path))
+}
+
+/// Splits a gRPC request path into its service and method, returning `None` unless the
+/// path is exactly `/{service}/{method}` with a non-empty service and method.
+///
+/// ```
+/// assert_eq!(
+///     tonic_metrics::parse_grpc_path_strict("/echo.Echo/Echo"),
+///     Some(("echo.Echo", "Echo"))
+/// );
+/// assert_eq!(tonic_metrics::parse_grpc_path_strict("/echo.Echo/Echo/extra"), None);
+/// ```
+pub fn parse_grpc_path_strict(path: &str) -> Option<(&str, &str)> {
+    let (service, method) = path.strip_prefix('/')?.split_once('/')?;
+
+    if service.is_empty() || method.is_empty() || method.contains('/') {
+        return None;
+    }
+
+    Some((service, method))
+}
+
+/// Joins a service and method parsed from a path back into `pkg.Service/Method`.
+///
+/// Paths that couldn't be parsed have an empty service, so only the method is returned.
+pub(crate) fn full_method(service: &str, method: &str) -> String {
+    if service.is_empty() {
+        method.to_string()
+    } else {
+        format!("{service}/{method}")
+    }
+}
+
+/// Splits a service such as `pkg.Service` on its last dot into its package and bare name.
+///
+/// Services without a package have an empty package.
+pub(crate) fn split_package(service: &str) -> (&str, &str) {
+    service.rsplit_once('.').unwrap_or(("", service))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{full_method, parse_grpc_path, parse_grpc_path_strict, split_package};
+
+    #[test]
+    fn parses_service_and_method() {
+        assert_eq!(parse_grpc_path("/echo.Echo/Echo"), ("echo.Echo", "Echo"));
+    }
+
+    #[test]
+    fn without_leading_slash() {
+        assert_eq!(parse_grpc_path("echo.Echo/Echo"), ("", "echo.Echo/Echo"));
+    }
+
+    #[test]
+    fn without_method_separator() {
+        assert_eq!(parse_grpc_path("/echo.Echo"), ("", "/echo.Echo"));
+        assert_eq!(parse_grpc_path("/"), ("", "/"));
+        assert_eq!(parse_grpc_path(""), ("", ""));
+    }
+
+    #[test]
+    fn with_trailing_slash() {
+        assert_eq!(parse_grpc_path("/echo.Echo/"), ("echo.Echo", ""));
+        assert_eq!(parse_grpc_path("/echo.Echo/Echo/"), ("echo.Echo", "Echo"));
+    }
+
+    #[test]
+    fn with_extra_segments() {
+        assert_eq!(
+            parse_grpc_path("/echo.Echo/Echo/extra"),
+            ("echo.Echo", "Echo")
+        );
+        assert_eq!(
+            parse_grpc_path("/echo.Echo/Echo/a/b/c"),
+            ("echo.Echo", "Echo")
+        );
+    }
+
+    #[test]
+    fn with_empty_service() {
+        assert_eq!(parse_grpc_path("//Echo"), ("", "Echo"));
+    }
+
+    #[test]
+    fn with_non_ascii_segments() {
+        // `/` is ASCII and never part of a multibyte character, so splitting on it always
+        // lands on a char boundary
+        assert_eq!(
+            parse_grpc_path("/пакет.Сервис/Метод"),
+            ("пакет.Сервис", "Метод")
+        );
+        assert_eq!(parse_grpc_path("/é/ü/ß"), ("é", "ü"));
+        assert_eq!(parse_grpc_path("/日本"), ("", "/日本"));
+        assert_eq!(
+            parse_grpc_path_strict("/echo.Écho/Écho"),
+            Some(("echo.Écho", "Écho"))
+        );
+    }
+
+    #[test]
+    fn with_percent_encoded_segments() {
+        // Paths aren't decoded, so an encoded `/` doesn't split the segment
+        assert_eq!(
+            parse_grpc_path("/echo.Echo/Echo%2Fextra"),
+            ("echo.Echo", "Echo%2Fextra")
+        );
+        assert_eq!(
+            parse_grpc_path("/echo%2EEcho/%C3%A9"),
+            ("echo%2EEcho", "%C3%A9")
+        );
+    }
+
+    #[test]
+    fn strict_accepts_well_formed_paths() {
+        assert_eq!(
+            parse_grpc_path_strict("/echo.Echo/Echo"),
+            Some(("echo.Echo", "Echo"))
+        );
+    }
+
+    #[test]
+    fn strict_rejects_malformed_paths() {
+        for path in [
+            "",
+            "/",
+            "/echo.Echo",
+            "/echo.Echo/",
+            "//Echo",
+            "echo.Echo/Echo",
+            "/echo.Echo/Echo/",
+            "/echo.Echo/Echo/extra",
+        ] {
+            assert_eq!(parse_grpc_path_strict(path), None, "{path}");
+        }
+    }
+
+    #[test]
+    fn splits_package() {
+        assert_eq!(split_package("echo.Echo"), ("echo", "Echo"));
+        assert_eq!(
+            split_package("com.example.v1.Echo"),
+            ("com.example.v1", "Echo")
+        );
+        assert_eq!(split_package("Echo"), ("", "Echo"));
+        assert_eq!(split_package(""), ("", ""));
+    }
+
+    #[test]
+    fn joins_full_method() {
+        assert_eq!(full_method("echo.Echo", "Echo"), "echo.Echo/Echo");
+        assert_eq!(full_method("", "/health"), "/health");
+    }
+}
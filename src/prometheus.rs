@@ -0,0 +1,70 @@
+use std::net::SocketAddr;
+
+use metrics_exporter_prometheus::{BuildError, Matcher, PrometheusBuilder};
+
+use crate::{DurationUnit, RPC_CLIENT_DURATION, RPC_SERVER_DURATION};
+
+/// Installs a global `metrics-exporter-prometheus` recorder serving the metrics on
+/// `http://{addr}/metrics`, with the default duration histogram buckets.
+///
+/// Must be called within a Tokio runtime or spawns one in a background thread, see
+/// [`PrometheusBuilder::install`].
+///
+/// ```no_run
+/// tonic_metrics::install_prometheus_recorder(([0, 0, 0, 0], 9000))?;
+/// # Ok::<(), metrics_exporter_prometheus::BuildError>(())
+/// ```
+pub fn install_prometheus_recorder(addr: impl Into<SocketAddr>) -> Result<(), BuildError> {
+    prometheus_builder()?.with_http_listener(addr).install()
+}
+
+/// Returns a [`PrometheusBuilder`] with [`DurationUnit::default_buckets`] configured for the
+/// default duration histogram names, in both units and with or without the unit suffix, for
+/// setups that need more than [`install_prometheus_recorder`].
+///
+/// Layers with a custom `metric_name` or buckets must configure them on the builder
+/// themselves. Series are kept forever by default, set [`PrometheusBuilder::idle_timeout`] to
+/// drop the ones created by high-cardinality labels once they stop being updated.
+pub fn prometheus_builder() -> Result<PrometheusBuilder, BuildError> {
+    let mut builder = PrometheusBuilder::new();
+    for name in [RPC_SERVER_DURATION, RPC_CLIENT_DURATION] {
+        let milliseconds = DurationUnit::Milliseconds.default_buckets();
+        builder = builder
+            .set_buckets_for_metric(Matcher::Full(name.to_string()), &milliseconds)?
+            .set_buckets_for_metric(
+                Matcher::Full(
+                    DurationUnit::Milliseconds
+                        .suffixed(name.into())
+                        .into_owned(),
+                ),
+                &milliseconds,
+            )?
+            .set_buckets_for_metric(
+                Matcher::Full(DurationUnit::Seconds.suffixed(name.into()).into_owned()),
+                &DurationUnit::Seconds.default_buckets(),
+            )?;
+    }
+    Ok(builder)
+}
+
+#[cfg(test)]
+mod tests {
+    use metrics::histogram;
+
+    use super::prometheus_builder;
+
+    #[test]
+    fn configures_default_buckets() {
+        let recorder = prometheus_builder().unwrap().build_recorder();
+        let handle = recorder.handle();
+
+        metrics::with_local_recorder(&recorder, || {
+            histogram!("rpc.server.duration").record(12.0);
+            histogram!("rpc.client.duration_seconds").record(0.012);
+        });
+
+        let rendered = handle.render();
+        assert!(rendered.contains(r#"rpc_server_duration_bucket{le="25"} 1"#));
+        assert!(rendered.contains(r#"rpc_client_duration_seconds_bucket{le="0.025"} 1"#));
+    }
+}
@@ -0,0 +1,51 @@
+use std::{borrow::Cow, time::Duration};
+
+use crate::{
+    DurationUnit, LabelKeys, MetricsRsSink, MetricsSink, RPC_SERVER_DURATION, RPC_SERVER_REQUESTS,
+    body::grpc_error_type,
+};
+
+/// Records a server RPC handled outside the middleware, such as in a custom interceptor, with
+/// the same labels [`ServerMetricsLayer`](crate::ServerMetricsLayer) uses by default.
+///
+/// The connection isn't known here, so `network.protocol.version` and `network.transport` are
+/// left out rather than guessed. Disable them on the layer with
+/// [`with_protocol_version_label`](crate::ServerMetricsLayerBuilder::with_protocol_version_label)
+/// and [`with_transport_label`](crate::ServerMetricsLayerBuilder::with_transport_label) to
+/// record both into the same series.
+///
+/// Both the `rpc.server.duration` histogram, in milliseconds, and the `rpc.server.requests`
+/// counter are recorded through the `metrics` crate.
+///
+/// ```
+/// use std::time::Duration;
+///
+/// tonic_metrics::record_server_rpc(
+///     "echo.Echo",
+///     "Echo",
+///     tonic::Code::Ok,
+///     Duration::from_millis(12),
+/// );
+/// ```
+pub fn record_server_rpc(service: &str, method: &str, status: tonic::Code, duration: Duration) {
+    let keys = LabelKeys::default();
+    let code = status as i32;
+
+    let mut labels = vec![
+        (keys.rpc_system, Cow::Borrowed("grpc")),
+        (keys.network_protocol_name, Cow::Borrowed("http")),
+        (keys.rpc_method, Cow::Owned(method.to_string())),
+        (keys.rpc_service, Cow::Owned(service.to_string())),
+        (keys.rpc_grpc_status_code, Cow::Owned(code.to_string())),
+    ];
+    if code != 0 {
+        labels.push((keys.error_type, grpc_error_type(code)));
+    }
+
+    MetricsRsSink.record_duration(
+        &Cow::Borrowed(RPC_SERVER_DURATION),
+        &labels,
+        DurationUnit::Milliseconds.convert(duration),
+    );
+    MetricsRsSink.increment_requests(&Cow::Borrowed(RPC_SERVER_REQUESTS), &labels);
+}
@@ -0,0 +1,50 @@
+use std::{
+    cell::Cell,
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+};
+
+thread_local! {
+    static STATE: Cell<u64> = Cell::new(seed());
+}
+
+/// Seeds each thread from the randomly keyed std hasher, which is always non-zero as required
+/// by xorshift.
+fn seed() -> u64 {
+    RandomState::new().build_hasher().finish() | 1
+}
+
+/// Returns a uniformly distributed value in `[0, 1)` from a thread-local xorshift64* generator.
+fn next_f64() -> f64 {
+    STATE.with(|state| {
+        let mut x = state.get();
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        state.set(x);
+        // The top 53 bits fill the mantissa of an f64
+        (x.wrapping_mul(0x2545_f491_4f6c_dd1d) >> 11) as f64 / (1u64 << 53) as f64
+    })
+}
+
+/// Decides whether an RPC is recorded, `rate` being the fraction in `[0, 1]` that is.
+pub(crate) fn sampled(rate: f64) -> bool {
+    rate >= 1.0 || next_f64() < rate
+}
+
+#[cfg(test)]
+mod tests {
+    use super::sampled;
+
+    #[test]
+    fn respects_bounds() {
+        assert!((0..1_000).all(|_| sampled(1.0)));
+        assert!((0..1_000).all(|_| !sampled(0.0)));
+    }
+
+    #[test]
+    fn samples_the_given_fraction() {
+        let count = (0..100_000).filter(|_| sampled(0.1)).count();
+        assert!((9_000..11_000).contains(&count), "sampled {count}");
+    }
+}
@@ -0,0 +1,77 @@
+use std::{borrow::Cow, fmt, sync::Arc};
+
+#[cfg(feature = "metrics")]
+use metrics::{counter, histogram};
+
+use crate::{Label, core::RpcMeasurement};
+
+/// Backend the per-RPC duration histogram and request counter are recorded to.
+///
+/// Defaults to `MetricsRsSink`, which records through the `metrics` crate, or to a sink that
+/// discards everything without the `metrics` feature. A custom sink can forward to another
+/// metrics library, or capture recordings in tests without installing a recorder.
+// The names are taken as `Cow` rather than `&str` so sinks can turn them into owned metric keys
+// without copying them.
+#[allow(clippy::ptr_arg)]
+pub trait MetricsSink: Send + Sync + 'static {
+    /// Records the duration of a completed RPC, in the configured
+    /// [`DurationUnit`](crate::DurationUnit).
+    fn record_duration(&self, name: &Cow<'static, str>, labels: &[Label], duration: f64);
+
+    /// Counts a completed RPC.
+    fn increment_requests(&self, name: &Cow<'static, str>, labels: &[Label]);
+
+    /// Receives every completed RPC before its duration and count are recorded, does nothing
+    /// by default.
+    ///
+    /// Sinks that consume the measurements directly can leave the other methods empty.
+    fn record_measurement(&self, measurement: &RpcMeasurement<'_>) {
+        let _ = measurement;
+    }
+}
+
+/// Records through the `metrics` crate macros.
+#[cfg(feature = "metrics")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MetricsRsSink;
+
+#[cfg(feature = "metrics")]
+impl MetricsSink for MetricsRsSink {
+    fn record_duration(&self, name: &Cow<'static, str>, labels: &[Label], duration: f64) {
+        histogram!(name.clone(), labels).record(duration);
+    }
+
+    fn increment_requests(&self, name: &Cow<'static, str>, labels: &[Label]) {
+        counter!(name.clone(), labels).increment(1);
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct Sink(pub(crate) Arc<dyn MetricsSink>);
+
+/// The default sink without the `metrics` feature, only custom sinks receive measurements.
+#[cfg(not(feature = "metrics"))]
+struct NoopSink;
+
+#[cfg(not(feature = "metrics"))]
+impl MetricsSink for NoopSink {
+    fn record_duration(&self, _name: &Cow<'static, str>, _labels: &[Label], _duration: f64) {}
+
+    fn increment_requests(&self, _name: &Cow<'static, str>, _labels: &[Label]) {}
+}
+
+impl Default for Sink {
+    fn default() -> Self {
+        #[cfg(feature = "metrics")]
+        let sink = MetricsRsSink;
+        #[cfg(not(feature = "metrics"))]
+        let sink = NoopSink;
+        Self(Arc::new(sink))
+    }
+}
+
+impl fmt::Debug for Sink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Sink")
+    }
+}
@@ -1,10 +1,39 @@
-use metrics_util::debugging::{DebuggingRecorder, Snapshotter};
-use tokio::test;
+use std::{
+    borrow::Cow,
+    convert::Infallible,
+    future::{self, Future},
+    net::SocketAddr,
+    panic::{self, AssertUnwindSafe},
+    pin::{Pin, pin},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU32, Ordering},
+    },
+    task::{Context, Poll, Waker, ready},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use hyper_util::rt::TokioIo;
+use metrics::{LocalRecorderGuard, SharedString, Unit};
+use metrics_util::{
+    CompositeKey, MetricKind,
+    debugging::{DebugValue, DebuggingRecorder, Snapshotter},
+};
+use tokio::{task::JoinHandle, test};
 use tonic::{
     Request, Response, Status, async_trait,
-    transport::{Channel, Server},
+    codec::CompressionEncoding,
+    service::Interceptor,
+    transport::{Channel, Server, server::TcpIncoming},
+};
+use tonic_metrics::{
+    Clock, DurationUnit, Label, LabelKeys, MetricsHandle, MetricsInterceptor, MetricsSink,
+    RPC_CLIENT_DURATION, RPC_SERVER_DURATION, RpcStart, ServerMetricsLayer,
+    ServerMetricsMiddleware,
+    client::{ClientMetricsLayer, ClientMetricsMiddleware, RetryAttempt},
+    core::RpcMeasurement,
 };
-use tonic_metrics::{ServerMetricsLayer, client::ClientMetricsMiddleware};
+use tower::{Layer, Service, ServiceBuilder};
 
 mod echo;
 
@@ -14,7 +43,7 @@ use echo::{
     echo_server::{Echo, EchoServer},
 };
 
-const SNAPSHOT_FILTERS: [(&'static str, &'static str); 4] = [
+const SNAPSHOT_FILTERS: [(&str, &str); 6] = [
     (
         r"Histogram\(\s*[\s\S]*?\s*\)",
         "Histogram([HISTOGRAM_VALUE])",
@@ -23,34 +52,175 @@ const SNAPSHOT_FILTERS: [(&'static str, &'static str); 4] = [
         r#"Label\(\s*"server.port"\s*,\s*[\s\S]*?\s*\)"#,
         r#"Label("server.port", [PORT])"#,
     ),
+    (
+        r#"Label\(\s*"client.port"\s*,\s*[\s\S]*?\s*\)"#,
+        r#"Label("client.port", [PORT])"#,
+    ),
     (
         r#"Label\(\s*"port"\s*,\s*[\s\S]*?\s*\)"#,
         r#"Label("port", [PORT])"#,
     ),
     (r#"hash: \d*"#, "hash: [HASH]"),
+    (r"\[::1\]:\d+", "[::1]:[PORT]"),
 ];
 
 #[test]
 async fn basic_server_metrics() -> Result<(), Box<dyn std::error::Error>> {
-    let snapshotter = install_debug_recorder();
+    let (snapshotter, _guard) = install_debug_recorder();
 
-    let addr = "[::1]:50051".parse().unwrap();
-    let echo = MyEchoService::default();
+    let (addr, handle) = spawn_server(Some(ServerMetricsLayer::new())).await?;
 
-    println!("GreeterServer listening on {addr}");
+    send_request(&addr.to_string(), ClientMode::Plain)
+        .await
+        .unwrap();
 
-    let handle = tokio::spawn(async move {
-        Server::builder()
-            .layer(ServerMetricsLayer::default())
-            .add_service(EchoServer::new(echo))
-            .serve(addr)
-            .await
+    handle.abort();
+
+    let snapshot = snapshotter.snapshot();
+
+    println!("{:#?}", snapshot);
+    insta::with_settings!({filters => SNAPSHOT_FILTERS}, {
+        insta::assert_debug_snapshot!(snapshot);
+    });
+
+    Ok(())
+}
+
+#[test]
+async fn default_label_keys_server_metrics() -> Result<(), Box<dyn std::error::Error>> {
+    let (snapshotter, _guard) = install_debug_recorder();
+
+    let (addr, handle) = spawn_server(Some(ServerMetricsLayer::new())).await?;
+
+    send_request(&addr.to_string(), ClientMode::Plain)
+        .await
+        .unwrap();
+
+    handle.abort();
+
+    // The label keys are part of the public metric contract, any addition or removal must be
+    // deliberate
+    let snapshot = snapshotter.snapshot().into_vec();
+    let label_keys = |kind: MetricKind, name: &str| -> Vec<String> {
+        let (key, ..) = snapshot
+            .iter()
+            .find(|(key, ..)| key.kind() == kind && key.key().name() == name)
             .unwrap();
+        key.key()
+            .labels()
+            .map(|label| label.key().to_string())
+            .collect()
+    };
+    let rpc_labels = [
+        "rpc.system",
+        "network.protocol.name",
+        "network.transport",
+        "rpc.method",
+        "rpc.service",
+        "network.protocol.version",
+        "rpc.grpc.status_code",
+    ];
+    assert_eq!(
+        label_keys(MetricKind::Histogram, "rpc.server.duration"),
+        rpc_labels
+    );
+    assert_eq!(
+        label_keys(MetricKind::Counter, "rpc.server.requests"),
+        rpc_labels
+    );
+    assert_eq!(
+        label_keys(MetricKind::Gauge, "rpc.server.active_requests"),
+        ["rpc.method", "rpc.service"]
+    );
+
+    Ok(())
+}
+
+#[test]
+async fn custom_metric_name_server_metrics() -> Result<(), Box<dyn std::error::Error>> {
+    let (snapshotter, _guard) = install_debug_recorder();
+
+    let layer = ServerMetricsLayer::builder()
+        .metric_name("grpc_server_handling_seconds")
+        .build();
+    let (addr, handle) = spawn_server(Some(layer)).await?;
+
+    send_request(&addr.to_string(), ClientMode::Plain)
+        .await
+        .unwrap();
+
+    handle.abort();
+
+    let snapshot = snapshotter.snapshot();
+
+    println!("{:#?}", snapshot);
+    insta::with_settings!({filters => SNAPSHOT_FILTERS}, {
+        insta::assert_debug_snapshot!(snapshot);
     });
 
-    tokio::time::sleep(std::time::Duration::from_millis(150)).await;
+    Ok(())
+}
+
+#[test]
+async fn metric_description_server_metrics() -> Result<(), Box<dyn std::error::Error>> {
+    let (snapshotter, _guard) = install_debug_recorder();
+
+    let mut service = ServerMetricsLayer::builder()
+        .metric_description("Time spent serving an RPC")
+        .build()
+        .layer(ContentLengthService);
+    let request = http::Request::builder()
+        .uri("/echo.Echo/Echo")
+        .header("content-type", "application/grpc")
+        .body(tonic::body::Body::empty())?;
+    service.call(request).await?;
+
+    let snapshot = snapshotter.snapshot().into_vec();
+    let description = snapshot
+        .iter()
+        .find(|(key, ..)| key.key().name() == "rpc.server.duration")
+        .and_then(|(_, _, description, _)| description.as_deref());
+    assert_eq!(description, Some("Time spent serving an RPC"));
+
+    Ok(())
+}
+
+#[test]
+async fn metric_alias_server_metrics() -> Result<(), Box<dyn std::error::Error>> {
+    let (snapshotter, _guard) = install_debug_recorder();
+
+    let mut service = ServerMetricsLayer::builder()
+        .metric_name("rpc.server.call.duration")
+        .with_metric_alias("rpc.server.duration")
+        .build()
+        .layer(ContentLengthService);
+    let request = http::Request::builder()
+        .uri("/echo.Echo/Echo")
+        .header("content-type", "application/grpc")
+        .body(tonic::body::Body::empty())?;
+    service.call(request).await?;
+
+    let snapshot = snapshotter.snapshot().into_vec();
+    let new = histogram_values(&snapshot, "rpc.server.call.duration");
+    let old = histogram_values(&snapshot, "rpc.server.duration");
+    assert_eq!(new.len(), 1);
+    assert_eq!(new, old);
+
+    Ok(())
+}
+
+#[test]
+async fn seconds_duration_unit_server_metrics() -> Result<(), Box<dyn std::error::Error>> {
+    let (snapshotter, _guard) = install_debug_recorder();
+
+    let layer = ServerMetricsLayer::builder()
+        .duration_unit(DurationUnit::Seconds)
+        .build();
+    let (addr, handle) = spawn_server(Some(layer)).await?;
 
-    send_request(&addr.to_string(), false).await.unwrap();
+    send_request(&addr.to_string(), ClientMode::Plain)
+        .await
+        .unwrap();
 
     handle.abort();
 
@@ -65,25 +235,45 @@ async fn basic_server_metrics() -> Result<(), Box<dyn std::error::Error>> {
 }
 
 #[test]
-async fn basic_client_metrics() -> Result<(), Box<dyn std::error::Error>> {
-    let snapshotter = install_debug_recorder();
+async fn static_labels_server_metrics() -> Result<(), Box<dyn std::error::Error>> {
+    let (snapshotter, _guard) = install_debug_recorder();
 
-    let addr = "[::1]:50051".parse().unwrap();
-    let echo = MyEchoService::default();
+    let layer = ServerMetricsLayer::builder()
+        .with_labels([
+            ("service.name", "echo-server"),
+            ("deployment.environment", "test"),
+        ])
+        .build();
+    let (addr, handle) = spawn_server(Some(layer)).await?;
 
-    println!("GreeterServer listening on {addr}");
+    send_request(&addr.to_string(), ClientMode::Plain)
+        .await
+        .unwrap();
 
-    let handle = tokio::spawn(async move {
-        Server::builder()
-            .add_service(EchoServer::new(echo))
-            .serve(addr)
-            .await
-            .unwrap();
+    handle.abort();
+
+    let snapshot = snapshotter.snapshot();
+
+    println!("{:#?}", snapshot);
+    insta::with_settings!({filters => SNAPSHOT_FILTERS}, {
+        insta::assert_debug_snapshot!(snapshot);
     });
 
-    tokio::time::sleep(std::time::Duration::from_millis(150)).await;
+    Ok(())
+}
 
-    send_request(&addr.to_string(), true).await.unwrap();
+#[test]
+async fn rpc_system_server_metrics() -> Result<(), Box<dyn std::error::Error>> {
+    let (snapshotter, _guard) = install_debug_recorder();
+
+    let layer = ServerMetricsLayer::builder()
+        .rpc_system("connect_rpc")
+        .build();
+    let (addr, handle) = spawn_server(Some(layer)).await?;
+
+    send_request(&addr.to_string(), ClientMode::Plain)
+        .await
+        .unwrap();
 
     handle.abort();
 
@@ -97,51 +287,2691 @@ async fn basic_client_metrics() -> Result<(), Box<dyn std::error::Error>> {
     Ok(())
 }
 
-#[derive(Default)]
-pub struct MyEchoService;
+#[test]
+async fn on_response_server_metrics() -> Result<(), Box<dyn std::error::Error>> {
+    let (_snapshotter, _guard) = install_debug_recorder();
 
-#[async_trait]
-impl Echo for MyEchoService {
-    async fn echo(&self, request: Request<EchoRequest>) -> Result<Response<EchoResponse>, Status> {
-        println!("Got a request from {:?}", request.remote_addr());
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let layer = ServerMetricsLayer::builder()
+        .on_response({
+            let seen = seen.clone();
+            move |info| {
+                seen.lock().unwrap().push((
+                    info.service().to_string(),
+                    info.method().to_string(),
+                    info.grpc_status(),
+                    info.error_type().map(str::to_string),
+                    info.is_error(),
+                ));
+            }
+        })
+        .build();
+    let (addr, handle) = spawn_server(Some(layer)).await?;
 
-        let reply = EchoResponse {
-            message: request.into_inner().message,
-        };
-        Ok(Response::new(reply))
+    send_request(&addr.to_string(), ClientMode::Plain)
+        .await
+        .unwrap();
+
+    handle.abort();
+
+    assert_eq!(
+        *seen.lock().unwrap(),
+        vec![(
+            "echo.Echo".to_string(),
+            "Echo".to_string(),
+            Some(0),
+            None,
+            false
+        )]
+    );
+
+    Ok(())
+}
+
+#[test]
+async fn max_distinct_methods_server_metrics() -> Result<(), Box<dyn std::error::Error>> {
+    let (snapshotter, _guard) = install_debug_recorder();
+
+    let layer = ServerMetricsLayer::builder()
+        .with_max_distinct_methods(0)
+        .build();
+    let (addr, handle) = spawn_server(Some(layer)).await?;
+
+    send_request(&addr.to_string(), ClientMode::Plain)
+        .await
+        .unwrap();
+
+    handle.abort();
+
+    let snapshot = snapshotter.snapshot().into_vec();
+    for label in ["rpc.service", "rpc.method"] {
+        assert_eq!(
+            label_value(
+                &snapshot,
+                MetricKind::Histogram,
+                "rpc.server.duration",
+                label
+            )
+            .as_deref(),
+            Some("other")
+        );
     }
+
+    Ok(())
 }
 
-async fn send_request(
-    addr: &str,
-    use_client_middleware: bool,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let addr = format!("http://{addr}");
+#[test]
+async fn method_allowlist_server_metrics() -> Result<(), Box<dyn std::error::Error>> {
+    let (snapshotter, _guard) = install_debug_recorder();
 
-    let request = tonic::Request::new(EchoRequest {
-        message: "Hello".into(),
+    let mut service = ServerMetricsLayer::builder()
+        .with_method_allowlist([("echo.Echo", "Echo")])
+        .build()
+        .layer(ContentLengthService);
+    for path in ["/echo.Echo/Echo", "/echo.Echo/Secret", "/other.Other/Echo"] {
+        let request = http::Request::builder()
+            .uri(path)
+            .header("content-type", "application/grpc")
+            .body(tonic::body::Body::empty())?;
+        service.call(request).await?;
+    }
+
+    let snapshot = snapshotter.snapshot().into_vec();
+    let mut recorded: Vec<_> = snapshot
+        .iter()
+        .filter(|(key, ..)| {
+            key.kind() == MetricKind::Counter && key.key().name() == "rpc.server.requests"
+        })
+        .map(|(key, _, _, value)| {
+            let label = |name| {
+                key.key()
+                    .labels()
+                    .find(|l| l.key() == name)
+                    .map(|l| l.value().to_string())
+            };
+            (label("rpc.service"), label("rpc.method"), value)
+        })
+        .collect();
+    recorded.sort_by(|a, b| a.0.cmp(&b.0));
+
+    // Both unlisted methods share a single series
+    assert_eq!(
+        recorded,
+        vec![
+            (
+                Some("echo.Echo".to_string()),
+                Some("Echo".to_string()),
+                &DebugValue::Counter(1)
+            ),
+            (
+                Some("unknown".to_string()),
+                Some("unknown".to_string()),
+                &DebugValue::Counter(2)
+            ),
+        ]
+    );
+
+    Ok(())
+}
+
+#[test]
+async fn known_paths_server_metrics() -> Result<(), Box<dyn std::error::Error>> {
+    let (snapshotter, _guard) = install_debug_recorder();
+
+    let layer = ServerMetricsLayer::builder()
+        .with_known_paths(["/echo.Echo/Echo"])
+        .build();
+    let (addr, handle) = spawn_server(Some(layer)).await?;
+
+    send_request(&addr.to_string(), ClientMode::Plain)
+        .await
+        .unwrap();
+
+    handle.abort();
+
+    let snapshot = snapshotter.snapshot();
+
+    println!("{:#?}", snapshot);
+    // Interned labels must produce exactly the same metrics as parsed ones
+    insta::with_settings!({filters => SNAPSHOT_FILTERS}, {
+        insta::assert_debug_snapshot!("basic_server_metrics", snapshot);
     });
 
-    let response = if use_client_middleware {
-        let channel = Channel::from_shared(addr.to_string())?.connect().await?;
-        let metrics = ClientMetricsMiddleware::with_server_address(channel, Some(addr));
-        EchoClient::new(metrics).echo(request).await?
-    } else {
-        EchoClient::connect(addr)
-            .await
+    Ok(())
+}
+
+#[test]
+async fn server_address_server_metrics() -> Result<(), Box<dyn std::error::Error>> {
+    let (snapshotter, _guard) = install_debug_recorder();
+
+    let layer = ServerMetricsLayer::builder()
+        .with_server_address(true)
+        .build();
+    let (addr, handle) = spawn_server(Some(layer)).await?;
+
+    send_request(&addr.to_string(), ClientMode::Plain)
+        .await
+        .unwrap();
+
+    handle.abort();
+
+    let snapshot = snapshotter.snapshot();
+
+    println!("{:#?}", snapshot);
+    insta::with_settings!({filters => SNAPSHOT_FILTERS}, {
+        insta::assert_debug_snapshot!(snapshot);
+    });
+
+    Ok(())
+}
+
+#[test]
+async fn peer_labels_server_metrics() -> Result<(), Box<dyn std::error::Error>> {
+    let (snapshotter, _guard) = install_debug_recorder();
+
+    let layer = ServerMetricsLayer::builder().with_peer_labels(true).build();
+    let (addr, handle) = spawn_server(Some(layer)).await?;
+
+    send_request(&addr.to_string(), ClientMode::Plain)
+        .await
+        .unwrap();
+
+    handle.abort();
+
+    let snapshot = snapshotter.snapshot();
+
+    println!("{:#?}", snapshot);
+    insta::with_settings!({filters => SNAPSHOT_FILTERS}, {
+        insta::assert_debug_snapshot!(snapshot);
+    });
+
+    Ok(())
+}
+
+#[derive(Clone, Default)]
+struct RecordingSink {
+    durations: Arc<Mutex<Vec<String>>>,
+    requests: Arc<Mutex<Vec<String>>>,
+    measurements: Arc<Mutex<Vec<Option<i32>>>>,
+}
+
+impl MetricsSink for RecordingSink {
+    fn record_duration(&self, name: &Cow<'static, str>, _labels: &[Label], _duration: f64) {
+        self.durations.lock().unwrap().push(name.to_string());
+    }
+
+    fn increment_requests(&self, name: &Cow<'static, str>, _labels: &[Label]) {
+        self.requests.lock().unwrap().push(name.to_string());
+    }
+
+    fn record_measurement(&self, measurement: &RpcMeasurement<'_>) {
+        assert!(
+            measurement
+                .labels()
+                .iter()
+                .any(|(key, value)| *key == "rpc.method" && value == "Echo")
+        );
+        self.measurements
+            .lock()
             .unwrap()
-            .echo(request)
-            .await?
-    };
+            .push(measurement.grpc_status());
+    }
+}
 
-    println!("RESPONSE={response:?}");
+#[test]
+async fn metrics_sink_server_metrics() -> Result<(), Box<dyn std::error::Error>> {
+    // No recorder is installed, everything goes through the sink
+    let sink = RecordingSink::default();
+    let layer = ServerMetricsLayer::builder()
+        .metrics_sink(sink.clone())
+        .build();
+    let (addr, handle) = spawn_server(Some(layer)).await?;
+
+    send_request(&addr.to_string(), ClientMode::Plain)
+        .await
+        .unwrap();
+
+    handle.abort();
+
+    assert_eq!(*sink.durations.lock().unwrap(), vec!["rpc.server.duration"]);
+    assert_eq!(*sink.requests.lock().unwrap(), vec!["rpc.server.requests"]);
+    assert_eq!(*sink.measurements.lock().unwrap(), vec![Some(0)]);
+
+    Ok(())
+}
+
+#[test]
+async fn no_protocol_version_server_metrics() -> Result<(), Box<dyn std::error::Error>> {
+    let (snapshotter, _guard) = install_debug_recorder();
+
+    let layer = ServerMetricsLayer::builder()
+        .with_protocol_version_label(false)
+        .build();
+    let (addr, handle) = spawn_server(Some(layer)).await?;
+
+    send_request(&addr.to_string(), ClientMode::Plain)
+        .await
+        .unwrap();
+
+    handle.abort();
+
+    let snapshot = snapshotter.snapshot().into_vec();
+    assert_eq!(
+        label_value(
+            &snapshot,
+            MetricKind::Histogram,
+            "rpc.server.duration",
+            "network.protocol.version"
+        ),
+        None
+    );
+
+    Ok(())
+}
+
+#[test]
+async fn no_constant_labels_server_metrics() -> Result<(), Box<dyn std::error::Error>> {
+    let (snapshotter, _guard) = install_debug_recorder();
+
+    let layer = ServerMetricsLayer::builder()
+        .with_protocol_name_label(false)
+        .with_transport_label(false)
+        .build();
+    let (addr, handle) = spawn_server(Some(layer)).await?;
+
+    send_request(&addr.to_string(), ClientMode::Plain)
+        .await
+        .unwrap();
+
+    handle.abort();
+
+    let snapshot = snapshotter.snapshot().into_vec();
+    for label in ["network.protocol.name", "network.transport"] {
+        assert_eq!(
+            label_value(
+                &snapshot,
+                MetricKind::Histogram,
+                "rpc.server.duration",
+                label
+            ),
+            None
+        );
+    }
+
+    Ok(())
+}
+
+#[test]
+async fn full_method_label_server_metrics() -> Result<(), Box<dyn std::error::Error>> {
+    let (snapshotter, _guard) = install_debug_recorder();
+
+    let layer = ServerMetricsLayer::builder()
+        .with_full_method_label(true)
+        .build();
+    let (addr, handle) = spawn_server(Some(layer)).await?;
+
+    send_request(&addr.to_string(), ClientMode::Plain)
+        .await
+        .unwrap();
+
+    handle.abort();
+
+    let snapshot = snapshotter.snapshot().into_vec();
+    assert_eq!(
+        label_value(
+            &snapshot,
+            MetricKind::Histogram,
+            "rpc.server.duration",
+            "rpc.method.full"
+        )
+        .as_deref(),
+        Some("echo.Echo/Echo")
+    );
+
+    Ok(())
+}
+
+#[test]
+async fn package_label_server_metrics() -> Result<(), Box<dyn std::error::Error>> {
+    let (snapshotter, _guard) = install_debug_recorder();
+
+    let layer = ServerMetricsLayer::builder()
+        .with_package_label(true)
+        .with_full_method_label(true)
+        .build();
+    let (addr, handle) = spawn_server(Some(layer)).await?;
+
+    send_request(&addr.to_string(), ClientMode::Plain)
+        .await
+        .unwrap();
+
+    handle.abort();
+
+    let snapshot = snapshotter.snapshot().into_vec();
+    let label = |key| label_value(&snapshot, MetricKind::Histogram, "rpc.server.duration", key);
+    assert_eq!(label("rpc.package").as_deref(), Some("echo"));
+    assert_eq!(label("rpc.service").as_deref(), Some("Echo"));
+    assert_eq!(label("rpc.method.full").as_deref(), Some("echo.Echo/Echo"));
+
+    Ok(())
+}
+
+/// A service that never responds, to simulate a slow handler.
+#[derive(Clone)]
+struct PendingService;
+
+impl<B> Service<http::Request<B>> for PendingService {
+    type Response = http::Response<tonic::body::Body>;
+    type Error = Infallible;
+    type Future = future::Pending<Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, _req: http::Request<B>) -> Self::Future {
+        future::pending()
+    }
+}
+
+#[test]
+async fn cancelled_server_metrics() -> Result<(), Box<dyn std::error::Error>> {
+    let (snapshotter, _guard) = install_debug_recorder();
+
+    let mut service = ServerMetricsLayer::default().layer(PendingService);
+    let request = http::Request::builder()
+        .uri("/echo.Echo/Echo")
+        .header("content-type", "application/grpc")
+        .body(tonic::body::Body::empty())?;
+
+    // Dropping the response future before it completes, as hyper does when the client goes away
+    let result = tokio::time::timeout(Duration::from_millis(10), service.call(request)).await;
+    assert!(result.is_err());
+
+    let snapshot = snapshotter.snapshot();
+
+    println!("{:#?}", snapshot);
+    insta::with_settings!({filters => SNAPSHOT_FILTERS}, {
+        insta::assert_debug_snapshot!(snapshot);
+    });
+
+    Ok(())
+}
+
+#[test]
+async fn label_keys_server_metrics() -> Result<(), Box<dyn std::error::Error>> {
+    let (snapshotter, _guard) = install_debug_recorder();
+
+    let layer = ServerMetricsLayer::builder()
+        .label_keys(LabelKeys {
+            rpc_service: "grpc_service",
+            rpc_grpc_status_code: "grpc_code",
+            ..LabelKeys::default()
+        })
+        .build();
+    let (addr, handle) = spawn_server(Some(layer)).await?;
+
+    send_request(&addr.to_string(), ClientMode::Plain)
+        .await
+        .unwrap();
+
+    handle.abort();
+
+    let snapshot = snapshotter.snapshot();
+
+    println!("{:#?}", snapshot);
+    insta::with_settings!({filters => SNAPSHOT_FILTERS}, {
+        insta::assert_debug_snapshot!(snapshot);
+    });
 
     Ok(())
 }
 
-fn install_debug_recorder() -> Snapshotter {
-    let recorder = DebuggingRecorder::new();
+/// A service responding immediately with a `content-length`, which tonic never sets.
+#[derive(Clone)]
+struct ContentLengthService;
+
+impl<B> Service<http::Request<B>> for ContentLengthService {
+    type Response = http::Response<tonic::body::Body>;
+    type Error = Infallible;
+    type Future = future::Ready<Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, _req: http::Request<B>) -> Self::Future {
+        let response = http::Response::builder()
+            .header("content-length", "42")
+            .header("grpc-status", "0")
+            .body(tonic::body::Body::empty())
+            .unwrap();
+        future::ready(Ok(response))
+    }
+}
+
+/// A service responding immediately with the given HTTP status and no `grpc-status`.
+#[derive(Clone)]
+struct HttpStatusService(http::StatusCode);
+
+impl<B> Service<http::Request<B>> for HttpStatusService {
+    type Response = http::Response<tonic::body::Body>;
+    type Error = Infallible;
+    type Future = future::Ready<Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, _req: http::Request<B>) -> Self::Future {
+        let response = http::Response::builder()
+            .status(self.0)
+            .body(tonic::body::Body::empty())
+            .unwrap();
+        future::ready(Ok(response))
+    }
+}
+
+#[test]
+async fn plain_http_body_server_metrics() -> Result<(), Box<dyn std::error::Error>> {
+    let (snapshotter, _guard) = install_debug_recorder();
+
+    // Any `http_body::Body` works, not just tonic's
+    let mut service = ServerMetricsLayer::new().layer(ContentLengthService);
+    let request = http::Request::builder()
+        .uri("/echo.Echo/Echo")
+        .header("content-type", "application/grpc")
+        .body(String::new())?;
+    service.call(request).await?;
+
+    let snapshot = snapshotter.snapshot().into_vec();
+    assert_eq!(histogram_values(&snapshot, "rpc.server.duration").len(), 1);
+
+    Ok(())
+}
+
+#[test]
+async fn middleware_constructors_server_metrics() -> Result<(), Box<dyn std::error::Error>> {
+    let (snapshotter, _guard) = install_debug_recorder();
+
+    let layer = ServerMetricsLayer::builder()
+        .metric_name("custom.duration")
+        .build();
+    let mut default = ServerMetricsMiddleware::new(ContentLengthService);
+    let mut custom = ServerMetricsMiddleware::with_layer(ContentLengthService, &layer);
+    for service in [&mut default, &mut custom] {
+        let request = http::Request::builder()
+            .uri("/echo.Echo/Echo")
+            .header("content-type", "application/grpc")
+            .body(tonic::body::Body::empty())?;
+        service.call(request).await?;
+    }
+
+    let snapshot = snapshotter.snapshot().into_vec();
+    assert_eq!(histogram_values(&snapshot, "rpc.server.duration").len(), 1);
+    assert_eq!(histogram_values(&snapshot, "custom.duration").len(), 1);
+
+    Ok(())
+}
+
+/// A service that can't be cloned, e.g. because it owns a connection.
+struct NotCloneService(ContentLengthService);
+
+impl<B> Service<http::Request<B>> for NotCloneService {
+    type Response = http::Response<tonic::body::Body>;
+    type Error = Infallible;
+    type Future = future::Ready<Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Service::<http::Request<B>>::poll_ready(&mut self.0, cx)
+    }
+
+    fn call(&mut self, req: http::Request<B>) -> Self::Future {
+        self.0.call(req)
+    }
+}
+
+#[test]
+async fn not_clone_server_metrics() -> Result<(), Box<dyn std::error::Error>> {
+    let (snapshotter, _guard) = install_debug_recorder();
+
+    let mut service = ServerMetricsLayer::new().layer(NotCloneService(ContentLengthService));
+    let request = http::Request::builder()
+        .uri("/echo.Echo/Echo")
+        .header("content-type", "application/grpc")
+        .body(tonic::body::Body::empty())?;
+    service.call(request).await?;
+
+    let snapshot = snapshotter.snapshot().into_vec();
+    assert_eq!(histogram_values(&snapshot, "rpc.server.duration").len(), 1);
+
+    Ok(())
+}
+
+#[test]
+async fn header_labels_server_metrics() -> Result<(), Box<dyn std::error::Error>> {
+    let (snapshotter, _guard) = install_debug_recorder();
+
+    let mut service = ServerMetricsLayer::builder()
+        .with_header_label("x-tenant-id", "tenant")
+        .with_header_label("x-region", "region")
+        .build()
+        .layer(ContentLengthService);
+    let request = http::Request::builder()
+        .uri("/echo.Echo/Echo")
+        .header("content-type", "application/grpc")
+        .header("x-tenant-id", "acme")
+        .body(tonic::body::Body::empty())?;
+    service.call(request).await?;
+
+    let snapshot = snapshotter.snapshot().into_vec();
+    assert_eq!(
+        label_value(
+            &snapshot,
+            MetricKind::Counter,
+            "rpc.server.requests",
+            "tenant"
+        ),
+        Some("acme".to_string())
+    );
+    // Missing headers omit the label
+    assert_eq!(
+        label_value(
+            &snapshot,
+            MetricKind::Counter,
+            "rpc.server.requests",
+            "region"
+        ),
+        None
+    );
+
+    Ok(())
+}
+
+#[test]
+async fn baggage_labels_server_metrics() -> Result<(), Box<dyn std::error::Error>> {
+    let (snapshotter, _guard) = install_debug_recorder();
+
+    let mut service = ServerMetricsLayer::builder()
+        .with_baggage_label("tenant", "tenant")
+        .with_baggage_label("region", "region")
+        .build()
+        .layer(ContentLengthService);
+    let request = http::Request::builder()
+        .uri("/echo.Echo/Echo")
+        .header("content-type", "application/grpc")
+        .header("baggage", "user=alice,tenant=acme%20corp;ttl=60")
+        .body(tonic::body::Body::empty())?;
+    service.call(request).await?;
+
+    let snapshot = snapshotter.snapshot().into_vec();
+    assert_eq!(
+        label_value(
+            &snapshot,
+            MetricKind::Counter,
+            "rpc.server.requests",
+            "tenant"
+        ),
+        Some("acme corp".to_string())
+    );
+    // Missing entries omit the label
+    assert_eq!(
+        label_value(
+            &snapshot,
+            MetricKind::Counter,
+            "rpc.server.requests",
+            "region"
+        ),
+        None
+    );
+
+    Ok(())
+}
+
+#[test]
+async fn method_mapper_server_metrics() -> Result<(), Box<dyn std::error::Error>> {
+    let (snapshotter, _guard) = install_debug_recorder();
+
+    let mut service = ServerMetricsLayer::builder()
+        .with_method_mapper(|method| match method.strip_prefix("Get") {
+            Some(_) => Cow::Borrowed("Get"),
+            None => Cow::Borrowed(method),
+        })
+        .with_known_paths(["/echo.Echo/GetKnown"])
+        .build()
+        .layer(ContentLengthService);
+    for path in ["/echo.Echo/Get123", "/echo.Echo/GetKnown"] {
+        let request = http::Request::builder()
+            .uri(path)
+            .header("content-type", "application/grpc")
+            .body(tonic::body::Body::empty())?;
+        service.call(request).await?;
+    }
+
+    let snapshot = snapshotter.snapshot().into_vec();
+    let counters: Vec<_> = snapshot
+        .iter()
+        .filter(|(key, ..)| key.key().name() == "rpc.server.requests")
+        .collect();
+    // Both methods are collapsed into a single series
+    assert_eq!(counters.len(), 1);
+    assert_eq!(
+        label_value(
+            &snapshot,
+            MetricKind::Counter,
+            "rpc.server.requests",
+            "rpc.method"
+        ),
+        Some("Get".to_string())
+    );
+    assert_eq!(counters[0].3, DebugValue::Counter(2));
+
+    Ok(())
+}
+
+/// A service responding immediately with a trailers-only response with the given `grpc-status`.
+#[derive(Clone)]
+struct GrpcStatusService(tonic::Code);
+
+impl<B> Service<http::Request<B>> for GrpcStatusService {
+    type Response = http::Response<tonic::body::Body>;
+    type Error = Infallible;
+    type Future = future::Ready<Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, _req: http::Request<B>) -> Self::Future {
+        future::ready(Ok(Status::new(self.0, "").into_http()))
+    }
+}
+
+#[test]
+async fn record_server_rpc_metrics() -> Result<(), Box<dyn std::error::Error>> {
+    let (snapshotter, _guard) = install_debug_recorder();
+
+    let mut service = ServerMetricsLayer::builder()
+        .with_protocol_version_label(false)
+        .with_transport_label(false)
+        .build()
+        .layer(GrpcStatusService(tonic::Code::NotFound));
+    let request = http::Request::builder()
+        .uri("/echo.Echo/Echo")
+        .version(http::Version::HTTP_2)
+        .header("content-type", "application/grpc")
+        .body(tonic::body::Body::empty())?;
+    service.call(request).await?;
+    let middleware = snapshotter.snapshot().into_vec();
+
+    tonic_metrics::record_server_rpc(
+        "echo.Echo",
+        "Echo",
+        tonic::Code::NotFound,
+        Duration::from_millis(5),
+    );
+    let manual = snapshotter.snapshot().into_vec();
+
+    // Recorded with the same series as the middleware
+    let keys = |snapshot: &[(CompositeKey, Option<Unit>, Option<SharedString>, DebugValue)]| {
+        let mut keys: Vec<_> = snapshot
+            .iter()
+            .map(|(key, ..)| key.clone())
+            .filter(|key| key.kind() != MetricKind::Gauge)
+            .collect();
+        keys.sort();
+        keys
+    };
+    assert_eq!(keys(&manual), keys(&middleware));
+    assert_eq!(histogram_values(&manual, "rpc.server.duration"), [5.0]);
+
+    Ok(())
+}
+
+#[test]
+async fn interceptor_server_metrics() -> Result<(), Box<dyn std::error::Error>> {
+    let (snapshotter, _guard) = install_debug_recorder();
+
+    let mut interceptor = MetricsInterceptor;
+    let request = interceptor.call(Request::new(()))?;
+    let start = request
+        .extensions()
+        .get::<RpcStart>()
+        .expect("interceptor should insert the start time");
+    start.record("echo.Echo", "Echo", tonic::Code::Ok);
+
+    let snapshot = snapshotter.snapshot().into_vec();
+    assert_eq!(histogram_values(&snapshot, "rpc.server.duration").len(), 1);
+    assert_eq!(
+        label_value(
+            &snapshot,
+            MetricKind::Counter,
+            "rpc.server.requests",
+            "rpc.method"
+        ),
+        Some("Echo".to_string())
+    );
+
+    Ok(())
+}
+
+#[test]
+async fn status_code_names_server_metrics() -> Result<(), Box<dyn std::error::Error>> {
+    let (snapshotter, _guard) = install_debug_recorder();
+
+    let mut service = ServerMetricsLayer::builder()
+        .with_status_code_names(true)
+        .build()
+        .layer(GrpcStatusService(tonic::Code::NotFound));
+    let request = http::Request::builder()
+        .uri("/echo.Echo/Echo")
+        .header("content-type", "application/grpc")
+        .body(tonic::body::Body::empty())?;
+    service.call(request).await?;
+
+    let snapshot = snapshotter.snapshot().into_vec();
+    assert_eq!(
+        label_value(
+            &snapshot,
+            MetricKind::Counter,
+            "rpc.server.requests",
+            "rpc.grpc.status_code"
+        )
+        .as_deref(),
+        Some("NOT_FOUND")
+    );
+
+    Ok(())
+}
+
+#[test]
+async fn deadline_exceeded_server_metrics() -> Result<(), Box<dyn std::error::Error>> {
+    let (snapshotter, _guard) = install_debug_recorder();
+
+    let mut service =
+        ServerMetricsLayer::new().layer(GrpcStatusService(tonic::Code::DeadlineExceeded));
+    let request = http::Request::builder()
+        .uri("/echo.Echo/Echo")
+        .header("content-type", "application/grpc")
+        .body(tonic::body::Body::empty())?;
+    service.call(request).await?;
+
+    let snapshot = snapshotter.snapshot().into_vec();
+    assert_eq!(
+        label_value(
+            &snapshot,
+            MetricKind::Counter,
+            "rpc.server.requests",
+            "error.type"
+        ),
+        Some("deadline_exceeded".to_string())
+    );
+    assert_eq!(
+        label_value(
+            &snapshot,
+            MetricKind::Counter,
+            "rpc.server.requests",
+            "rpc.grpc.status_code"
+        ),
+        Some("4".to_string())
+    );
+
+    Ok(())
+}
+
+#[test]
+async fn result_label_server_metrics() -> Result<(), Box<dyn std::error::Error>> {
+    let (snapshotter, _guard) = install_debug_recorder();
+
+    for code in [tonic::Code::Ok, tonic::Code::NotFound] {
+        let mut service = ServerMetricsLayer::builder()
+            .with_result_label(true)
+            .build()
+            .layer(GrpcStatusService(code));
+        let request = http::Request::builder()
+            .uri("/echo.Echo/Echo")
+            .header("content-type", "application/grpc")
+            .body(tonic::body::Body::empty())?;
+        service.call(request).await?;
+    }
+
+    let snapshot = snapshotter.snapshot().into_vec();
+    let mut results: Vec<_> = snapshot
+        .iter()
+        .filter(|(key, ..)| key.key().name() == "rpc.server.requests")
+        .map(|(key, ..)| {
+            let labels: Vec<_> = key
+                .key()
+                .labels()
+                .map(|label| (label.key().to_string(), label.value().to_string()))
+                .collect();
+            let label = |name: &str| {
+                labels
+                    .iter()
+                    .find(|(key, _)| key == name)
+                    .map(|(_, value)| value.clone())
+            };
+            (label("result"), label("rpc.grpc.status_code"))
+        })
+        .collect();
+    results.sort();
+    assert_eq!(
+        results,
+        [
+            (Some("error".to_string()), Some("5".to_string())),
+            (Some("ok".to_string()), Some("0".to_string())),
+        ]
+    );
+
+    Ok(())
+}
+
+/// A streaming response body that sends the `grpc-status` trailer after the given delay.
+struct DelayedTrailersBody {
+    delay: Pin<Box<tokio::time::Sleep>>,
+    done: bool,
+}
+
+impl http_body::Body for DelayedTrailersBody {
+    type Data = bytes::Bytes;
+    type Error = Status;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<http_body::Frame<Self::Data>, Self::Error>>> {
+        if self.done {
+            return Poll::Ready(None);
+        }
+        ready!(self.delay.as_mut().poll(cx));
+        self.done = true;
+        let mut trailers = http::HeaderMap::new();
+        trailers.insert("grpc-status", "0".parse().unwrap());
+        Poll::Ready(Some(Ok(http_body::Frame::trailers(trailers))))
+    }
+}
+
+#[derive(Clone)]
+struct StreamingService;
+
+impl<B> Service<http::Request<B>> for StreamingService {
+    type Response = http::Response<tonic::body::Body>;
+    type Error = Infallible;
+    type Future = future::Ready<Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, _req: http::Request<B>) -> Self::Future {
+        let body = DelayedTrailersBody {
+            delay: Box::pin(tokio::time::sleep(STREAM_DURATION)),
+            done: false,
+        };
+        future::ready(Ok(http::Response::new(tonic::body::Body::new(body))))
+    }
+}
+
+const STREAM_DURATION: Duration = Duration::from_millis(100);
+
+/// A service whose response future panics, like a handler with a bug.
+#[derive(Clone)]
+struct PanicService;
+
+impl<B> Service<http::Request<B>> for PanicService {
+    type Response = http::Response<tonic::body::Body>;
+    type Error = Infallible;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, _req: http::Request<B>) -> Self::Future {
+        Box::pin(async { panic!("handler panicked") })
+    }
+}
+
+#[test]
+async fn panic_server_metrics() -> Result<(), Box<dyn std::error::Error>> {
+    let (snapshotter, _guard) = install_debug_recorder();
+
+    let mut service = ServerMetricsLayer::new().layer(PanicService);
+    let request = http::Request::builder()
+        .uri("/echo.Echo/Echo")
+        .header("content-type", "application/grpc")
+        .body(tonic::body::Body::empty())?;
+    let future = service.call(request);
+
+    let mut cx = Context::from_waker(Waker::noop());
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        let _ = pin!(future).poll(&mut cx);
+    }));
+    assert!(result.is_err());
+
+    let snapshot = snapshotter.snapshot().into_vec();
+    assert_eq!(
+        label_value(
+            &snapshot,
+            MetricKind::Counter,
+            "rpc.server.requests",
+            "error.type"
+        ),
+        Some("panic".to_string())
+    );
+    assert_eq!(histogram_values(&snapshot, "rpc.server.duration").len(), 1);
+    // The request is no longer counted as active
+    assert_eq!(
+        gauge_value(&snapshot, "rpc.server.active_requests"),
+        Some(0.0)
+    );
+
+    Ok(())
+}
+
+#[test]
+async fn streaming_server_metrics() -> Result<(), Box<dyn std::error::Error>> {
+    let (snapshotter, _guard) = install_debug_recorder();
+
+    let mut service = ServerMetricsLayer::new().layer(StreamingService);
+    let request = http::Request::builder()
+        .uri("/echo.Echo/Echo")
+        .header("content-type", "application/grpc")
+        .body(tonic::body::Body::empty())?;
+    let mut body = pin!(service.call(request).await?.into_body());
+
+    while future::poll_fn(|cx| http_body::Body::poll_frame(body.as_mut(), cx))
+        .await
+        .is_some()
+    {}
+
+    // The RPC lasts until the trailers are sent, not just until the response headers
+    let snapshot = snapshotter.snapshot().into_vec();
+    let durations = histogram_values(&snapshot, "rpc.server.duration");
+    assert_eq!(durations.len(), 1);
+    assert!(durations[0] >= STREAM_DURATION.as_millis() as f64);
+    assert_eq!(
+        gauge_value(&snapshot, "rpc.server.active_requests"),
+        Some(0.0)
+    );
+
+    Ok(())
+}
+
+/// A streaming response body that sends a single message, then never ends.
+struct StalledBody {
+    sent: bool,
+}
+
+impl http_body::Body for StalledBody {
+    type Data = bytes::Bytes;
+    type Error = Status;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<http_body::Frame<Self::Data>, Self::Error>>> {
+        if self.sent {
+            return Poll::Pending;
+        }
+        self.sent = true;
+        let message = bytes::Bytes::from_static(b"\0\0\0\0\0");
+        Poll::Ready(Some(Ok(http_body::Frame::data(message))))
+    }
+}
+
+#[derive(Clone)]
+struct StalledService;
+
+impl<B> Service<http::Request<B>> for StalledService {
+    type Response = http::Response<tonic::body::Body>;
+    type Error = Infallible;
+    type Future = future::Ready<Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, _req: http::Request<B>) -> Self::Future {
+        let body = StalledBody { sent: false };
+        future::ready(Ok(http::Response::new(tonic::body::Body::new(body))))
+    }
+}
+
+#[test]
+async fn cancelled_stream_server_metrics() -> Result<(), Box<dyn std::error::Error>> {
+    let (snapshotter, _guard) = install_debug_recorder();
+
+    let mut service = ServerMetricsLayer::new().layer(StalledService);
+    let request = http::Request::builder()
+        .uri("/echo.Echo/Echo")
+        .header("content-type", "application/grpc")
+        .body(tonic::body::Body::empty())?;
+    let mut body = Box::pin(service.call(request).await?.into_body());
+
+    // Dropping the body mid-stream, as hyper does when the client resets the stream
+    let frame = future::poll_fn(|cx| http_body::Body::poll_frame(body.as_mut(), cx)).await;
+    assert!(frame.is_some_and(|frame| frame.is_ok()));
+    drop(body);
+
+    let snapshot = snapshotter.snapshot().into_vec();
+    let label = |key| label_value(&snapshot, MetricKind::Counter, "rpc.server.requests", key);
+    assert_eq!(label("rpc.grpc.status_code").as_deref(), Some("1"));
+    assert_eq!(label("error.type").as_deref(), Some("cancelled"));
+    assert_eq!(
+        gauge_value(&snapshot, "rpc.server.active_requests"),
+        Some(0.0)
+    );
+
+    Ok(())
+}
+
+/// A streaming response body that sends a single message, then fails.
+struct FailingBody {
+    sent: bool,
+}
+
+impl http_body::Body for FailingBody {
+    type Data = bytes::Bytes;
+    type Error = Status;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<http_body::Frame<Self::Data>, Self::Error>>> {
+        if self.sent {
+            return Poll::Ready(Some(Err(Status::internal("connection lost"))));
+        }
+        self.sent = true;
+        let message = bytes::Bytes::from_static(b"\0\0\0\0\0");
+        Poll::Ready(Some(Ok(http_body::Frame::data(message))))
+    }
+}
+
+#[derive(Clone)]
+struct FailingService;
+
+impl<B> Service<http::Request<B>> for FailingService {
+    type Response = http::Response<tonic::body::Body>;
+    type Error = Infallible;
+    type Future = future::Ready<Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, _req: http::Request<B>) -> Self::Future {
+        let body = FailingBody { sent: false };
+        future::ready(Ok(http::Response::new(tonic::body::Body::new(body))))
+    }
+}
+
+#[test]
+async fn stream_error_server_metrics() -> Result<(), Box<dyn std::error::Error>> {
+    let (snapshotter, _guard) = install_debug_recorder();
+
+    let mut service = ServerMetricsLayer::new().layer(FailingService);
+    let request = http::Request::builder()
+        .uri("/echo.Echo/Echo")
+        .header("content-type", "application/grpc")
+        .body(tonic::body::Body::empty())?;
+    let mut body = pin!(service.call(request).await?.into_body());
+
+    let frame = future::poll_fn(|cx| http_body::Body::poll_frame(body.as_mut(), cx)).await;
+    assert!(frame.is_some_and(|frame| frame.is_ok()));
+    let frame = future::poll_fn(|cx| http_body::Body::poll_frame(body.as_mut(), cx)).await;
+    assert!(frame.is_some_and(|frame| frame.is_err()));
+
+    let snapshot = snapshotter.snapshot().into_vec();
+    assert_eq!(
+        label_value(
+            &snapshot,
+            MetricKind::Counter,
+            "rpc.server.requests",
+            "error.type"
+        )
+        .as_deref(),
+        Some("stream_error")
+    );
+    assert_eq!(histogram_values(&snapshot, "rpc.server.duration").len(), 1);
+
+    Ok(())
+}
+
+#[test]
+async fn http_error_server_metrics() -> Result<(), Box<dyn std::error::Error>> {
+    let (snapshotter, _guard) = install_debug_recorder();
+
+    let mut service =
+        ServerMetricsLayer::new().layer(HttpStatusService(http::StatusCode::NOT_FOUND));
+    let request = http::Request::builder()
+        .uri("/echo.Echo/Echo")
+        .header("content-type", "application/grpc")
+        .body(tonic::body::Body::empty())?;
+    // The metrics are recorded once the body ends
+    drop(service.call(request).await?);
+
+    let snapshot = snapshotter.snapshot().into_vec();
+    assert_eq!(
+        label_value(
+            &snapshot,
+            MetricKind::Counter,
+            "rpc.server.requests",
+            "error.type"
+        ),
+        Some("404".to_string())
+    );
+    assert_eq!(
+        label_value(
+            &snapshot,
+            MetricKind::Counter,
+            "rpc.server.requests",
+            "rpc.grpc.status_code"
+        ),
+        Some("12".to_string())
+    );
+
+    Ok(())
+}
+
+#[test]
+async fn malformed_response_server_metrics() -> Result<(), Box<dyn std::error::Error>> {
+    let (snapshotter, _guard) = install_debug_recorder();
+
+    // HTTP 200 without any messages or grpc-status
+    let (addr, handle) = spawn_service(
+        ServerMetricsLayer::new(),
+        HttpStatusService(http::StatusCode::OK),
+    )
+    .await?;
+    let request = http::Request::builder()
+        .uri("/echo.Echo/Echo")
+        .header("content-type", "application/grpc")
+        .body(tonic::body::Body::empty())?;
+    send_http_request(addr, request).await?;
+
+    handle.abort();
+
+    let snapshot = snapshotter.snapshot().into_vec();
+    assert_eq!(
+        label_value(
+            &snapshot,
+            MetricKind::Counter,
+            "rpc.server.requests",
+            "error.type"
+        ),
+        Some("malformed_response".to_string())
+    );
+    assert_eq!(
+        label_value(
+            &snapshot,
+            MetricKind::Counter,
+            "rpc.server.requests",
+            "rpc.grpc.status_code"
+        ),
+        Some("13".to_string())
+    );
+
+    Ok(())
+}
+
+/// A service responding like a Connect server to a unary RPC that wasn't found.
+#[derive(Clone)]
+struct ConnectErrorService;
+
+impl<B> Service<http::Request<B>> for ConnectErrorService {
+    type Response = http::Response<String>;
+    type Error = Infallible;
+    type Future = future::Ready<Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, _req: http::Request<B>) -> Self::Future {
+        let response = http::Response::builder()
+            .status(http::StatusCode::NOT_FOUND)
+            .header("content-type", "application/json")
+            .body(r#"{"code":"not_found","message":"no such echo"}"#.to_string())
+            .unwrap();
+        future::ready(Ok(response))
+    }
+}
+
+#[test]
+async fn connect_server_metrics() -> Result<(), Box<dyn std::error::Error>> {
+    let (snapshotter, _guard) = install_debug_recorder();
+
+    let (addr, handle) = spawn_service(ServerMetricsLayer::new(), ConnectErrorService).await?;
+    let request = http::Request::builder()
+        .uri("/echo.Echo/Echo")
+        .header("content-type", "application/json")
+        .header("connect-protocol-version", "1")
+        .body(tonic::body::Body::empty())?;
+    let status = send_http_request(addr, request).await?;
+    assert_eq!(status, http::StatusCode::NOT_FOUND);
+
+    handle.abort();
+
+    let snapshot = snapshotter.snapshot().into_vec();
+    let label = |key| label_value(&snapshot, MetricKind::Counter, "rpc.server.requests", key);
+    assert_eq!(label("rpc.system").as_deref(), Some("connect_rpc"));
+    assert_eq!(label("error.type").as_deref(), Some("not_found"));
+    assert_eq!(label("rpc.grpc.status_code").as_deref(), Some("5"));
+
+    Ok(())
+}
+
+/// Echoes a unary Connect request, whose body isn't length-prefixed.
+#[derive(Clone)]
+struct ConnectEchoService;
+
+impl<B> Service<http::Request<B>> for ConnectEchoService {
+    type Response = http::Response<String>;
+    type Error = Infallible;
+    type Future = future::Ready<Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, _req: http::Request<B>) -> Self::Future {
+        let response = http::Response::builder()
+            .header("content-type", "application/proto")
+            .body("hello".to_string())
+            .unwrap();
+        future::ready(Ok(response))
+    }
+}
+
+#[test]
+async fn connect_unary_message_metrics() -> Result<(), Box<dyn std::error::Error>> {
+    let (snapshotter, _guard) = install_debug_recorder();
+
+    let layer = ServerMetricsLayer::builder()
+        .with_message_sizes(true)
+        .with_messages_per_rpc(true)
+        .build();
+    let (addr, handle) = spawn_service(layer, ConnectEchoService).await?;
+    let request = http::Request::builder()
+        .uri("/echo.Echo/Echo")
+        .header("content-type", "application/proto")
+        .header("connect-protocol-version", "1")
+        .body(tonic::body::Body::new("hello".to_string()))?;
+    let status = send_http_request(addr, request).await?;
+    assert_eq!(status, http::StatusCode::OK);
+
+    handle.abort();
+
+    // The bare message bodies would otherwise be decoded as a frame prefix
+    let snapshot = snapshotter.snapshot().into_vec();
+    for name in [
+        "rpc.server.request.size",
+        "rpc.server.response.size",
+        "rpc.server.requests_per_rpc",
+        "rpc.server.responses_per_rpc",
+    ] {
+        assert_eq!(
+            histogram_values(&snapshot, name),
+            Vec::<f64>::new(),
+            "{name}"
+        );
+    }
+    assert_eq!(
+        label_value(
+            &snapshot,
+            MetricKind::Counter,
+            "rpc.server.requests",
+            "rpc.system"
+        ),
+        Some("connect_rpc".to_string())
+    );
+
+    Ok(())
+}
+
+#[test]
+async fn http_status_label_server_metrics() -> Result<(), Box<dyn std::error::Error>> {
+    let (snapshotter, _guard) = install_debug_recorder();
+
+    let mut service = ServerMetricsLayer::builder()
+        .with_http_status_label(true)
+        .build()
+        .layer(HttpStatusService(http::StatusCode::SERVICE_UNAVAILABLE));
+    let request = http::Request::builder()
+        .uri("/echo.Echo/Echo")
+        .header("content-type", "application/grpc")
+        .body(tonic::body::Body::empty())?;
+    service.call(request).await?;
+
+    let snapshot = snapshotter.snapshot().into_vec();
+    assert_eq!(
+        label_value(
+            &snapshot,
+            MetricKind::Counter,
+            "rpc.server.requests",
+            "http.response.status_code"
+        ),
+        Some("503".to_string())
+    );
+    // The gRPC status derived from the HTTP status is still recorded
+    assert_eq!(
+        label_value(
+            &snapshot,
+            MetricKind::Counter,
+            "rpc.server.requests",
+            "rpc.grpc.status_code"
+        ),
+        Some("14".to_string())
+    );
+
+    Ok(())
+}
+
+#[test]
+async fn response_content_length_server_metrics() -> Result<(), Box<dyn std::error::Error>> {
+    let (snapshotter, _guard) = install_debug_recorder();
+
+    let mut service = ServerMetricsLayer::builder()
+        .with_response_content_length(true)
+        .build()
+        .layer(ContentLengthService);
+    let request = http::Request::builder()
+        .uri("/echo.Echo/Echo")
+        .header("content-type", "application/grpc")
+        .body(tonic::body::Body::empty())?;
+    service.call(request).await?;
+
+    let snapshot = snapshotter.snapshot().into_vec();
+    assert_eq!(
+        histogram_values(&snapshot, "rpc.server.response.size"),
+        vec![42.0]
+    );
+
+    Ok(())
+}
+
+#[test]
+async fn request_content_length_server_metrics() -> Result<(), Box<dyn std::error::Error>> {
+    let (snapshotter, _guard) = install_debug_recorder();
+
+    let mut service = ServerMetricsLayer::builder()
+        .with_request_content_length(true)
+        .build()
+        .layer(ContentLengthService);
+    let request = http::Request::builder()
+        .uri("/echo.Echo/Echo")
+        .header("content-type", "application/grpc")
+        .header("content-length", "17")
+        .body(tonic::body::Body::empty())?;
+    service.call(request).await?;
+
+    // Requests without a content-length aren't recorded
+    let request = http::Request::builder()
+        .uri("/echo.Echo/Echo")
+        .header("content-type", "application/grpc")
+        .body(tonic::body::Body::empty())?;
+    service.call(request).await?;
+
+    let snapshot = snapshotter.snapshot().into_vec();
+    assert_eq!(
+        histogram_values(&snapshot, "rpc.server.request.size"),
+        vec![17.0]
+    );
+
+    Ok(())
+}
+
+#[test]
+async fn disabled_handle_server_metrics() -> Result<(), Box<dyn std::error::Error>> {
+    let (snapshotter, _guard) = install_debug_recorder();
+
+    let handle = MetricsHandle::new();
+    let layer = ServerMetricsLayer::builder().handle(handle.clone()).build();
+    let mut service = layer.layer(GrpcStatusService(tonic::Code::Ok));
+    let request = || {
+        http::Request::builder()
+            .uri("/echo.Echo/Echo")
+            .header("content-type", "application/grpc")
+            .body(tonic::body::Body::empty())
+    };
+
+    handle.disable();
+    service.call(request()?).await?;
+    assert!(snapshotter.snapshot().into_vec().is_empty());
+
+    handle.enable();
+    service.call(request()?).await?;
+    let snapshot = snapshotter.snapshot().into_vec();
+    assert_eq!(histogram_values(&snapshot, "rpc.server.duration").len(), 1);
+
+    Ok(())
+}
+
+#[test]
+async fn connect_duration_client_metrics() -> Result<(), Box<dyn std::error::Error>> {
+    let (snapshotter, _guard) = install_debug_recorder();
+
+    let (addr, handle) = spawn_server(None).await?;
+
+    let connector = tower::service_fn(|uri: http::Uri| async move {
+        let host = uri.host().unwrap_or_default();
+        let port = uri.port_u16().unwrap_or(80);
+        tokio::net::TcpStream::connect(format!("{host}:{port}"))
+            .await
+            .map(TokioIo::new)
+    });
+    let layer = ClientMetricsLayer::builder()
+        .duration_unit(DurationUnit::Seconds)
+        .label_keys(LabelKeys {
+            server_address: "peer_host",
+            ..LabelKeys::default()
+        })
+        .build();
+    let channel = Channel::from_shared(format!("http://{addr}"))?
+        .connect_with_connector_lazy(layer.connector(connector));
+    let mut client = EchoClient::new(layer.layer(channel));
+    for _ in 0..2 {
+        client
+            .echo(tonic::Request::new(EchoRequest {
+                message: "Hello".into(),
+            }))
+            .await?;
+    }
+
+    handle.abort();
+
+    let snapshot = snapshotter.snapshot().into_vec();
+    // The connection is reused, so it is only established once
+    assert_eq!(
+        histogram_values(&snapshot, "rpc.client.connect.duration").len(),
+        1
+    );
+    assert_eq!(histogram_values(&snapshot, "rpc.client.duration").len(), 2);
+    assert_eq!(
+        label_value(
+            &snapshot,
+            MetricKind::Histogram,
+            "rpc.client.connect.duration",
+            "peer_host"
+        )
+        .as_deref(),
+        Some("::1")
+    );
+    let (_, unit, ..) = snapshot
+        .iter()
+        .find(|(key, ..)| key.key().name() == "rpc.client.connect.duration")
+        .expect("connect duration should be recorded");
+    assert_eq!(*unit, Some(Unit::Seconds));
+
+    Ok(())
+}
+
+#[test]
+async fn disabled_handle_client_metrics() -> Result<(), Box<dyn std::error::Error>> {
+    let (snapshotter, _guard) = install_debug_recorder();
+
+    let layer = ClientMetricsLayer::new();
+    layer.handle().disable();
+    let mut service = layer.layer(GrpcStatusService(tonic::Code::Ok));
+    let request = http::Request::builder()
+        .uri("http://[::1]:50051/echo.Echo/Echo")
+        .body(tonic::body::Body::empty())?;
+    service.call(request).await?;
+
+    assert!(snapshotter.snapshot().into_vec().is_empty());
+
+    Ok(())
+}
+
+#[test]
+async fn without_active_requests_server_metrics() -> Result<(), Box<dyn std::error::Error>> {
+    let (snapshotter, _guard) = install_debug_recorder();
+
+    let layer = ServerMetricsLayer::builder()
+        .with_active_requests(false)
+        .build();
+    let (addr, handle) = spawn_server(Some(layer)).await?;
+
+    send_request(&addr.to_string(), ClientMode::Plain)
+        .await
+        .unwrap();
+
+    handle.abort();
+
+    let snapshot = snapshotter.snapshot().into_vec();
+    let names: Vec<_> = snapshot
+        .iter()
+        .map(|(key, ..)| key.key().name().to_string())
+        .collect();
+    assert_eq!(names.len(), 2, "{names:?}");
+    assert!(
+        !names
+            .iter()
+            .any(|name| name == "rpc.server.active_requests")
+    );
+
+    Ok(())
+}
+
+/// A clock advancing by a fixed step every time it is read.
+struct StepClock {
+    start: Instant,
+    step: Duration,
+    reads: AtomicU32,
+}
+
+impl Clock for StepClock {
+    fn now(&self) -> Instant {
+        self.start + self.step * self.reads.fetch_add(1, Ordering::Relaxed)
+    }
+}
+
+#[test]
+async fn clock_server_metrics() -> Result<(), Box<dyn std::error::Error>> {
+    let (snapshotter, _guard) = install_debug_recorder();
+
+    let mut service = ServerMetricsLayer::builder()
+        .clock(StepClock {
+            start: Instant::now(),
+            step: Duration::from_millis(250),
+            reads: AtomicU32::new(0),
+        })
+        .build()
+        .layer(ContentLengthService);
+    let request = http::Request::builder()
+        .uri("/echo.Echo/Echo")
+        .header("content-type", "application/grpc")
+        .body(tonic::body::Body::empty())?;
+    service.call(request).await?;
+
+    let snapshot = snapshotter.snapshot().into_vec();
+    assert_eq!(
+        histogram_values(&snapshot, "rpc.server.duration"),
+        vec![250.0]
+    );
+
+    Ok(())
+}
+
+/// A service that isn't ready on the first `poll_ready`, like one behind a concurrency limit.
+struct SlowReadyService {
+    ready: bool,
+}
+
+impl<B> Service<http::Request<B>> for SlowReadyService {
+    type Response = http::Response<tonic::body::Body>;
+    type Error = Infallible;
+    type Future = future::Ready<Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        if std::mem::replace(&mut self.ready, true) {
+            Poll::Ready(Ok(()))
+        } else {
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+    }
+
+    fn call(&mut self, req: http::Request<B>) -> Self::Future {
+        ContentLengthService.call(req)
+    }
+}
+
+#[test]
+async fn queue_duration_server_metrics() -> Result<(), Box<dyn std::error::Error>> {
+    let (snapshotter, _guard) = install_debug_recorder();
+
+    let mut service = ServerMetricsLayer::builder()
+        .with_queue_duration(true)
+        .clock(StepClock {
+            start: Instant::now(),
+            step: Duration::from_millis(250),
+            reads: AtomicU32::new(0),
+        })
+        .build()
+        .layer(SlowReadyService { ready: false });
+    future::poll_fn(|cx| Service::<http::Request<tonic::body::Body>>::poll_ready(&mut service, cx))
+        .await?;
+    let request = http::Request::builder()
+        .uri("/echo.Echo/Echo")
+        .header("content-type", "application/grpc")
+        .body(tonic::body::Body::empty())?;
+    service.call(request).await?;
+
+    // The clock is read once when first polled for readiness, once when called and once when
+    // the RPC completes
+    let snapshot = snapshotter.snapshot().into_vec();
+    assert_eq!(
+        histogram_values(&snapshot, "rpc.server.queue.duration"),
+        vec![250.0]
+    );
+    assert_eq!(
+        histogram_values(&snapshot, "rpc.server.duration"),
+        vec![250.0]
+    );
+
+    Ok(())
+}
+
+#[test]
+async fn overhead_server_metrics() -> Result<(), Box<dyn std::error::Error>> {
+    let (snapshotter, _guard) = install_debug_recorder();
+
+    let mut service = ServerMetricsLayer::builder()
+        .with_overhead(true)
+        .build()
+        .layer(ContentLengthService);
+    let request = http::Request::builder()
+        .uri("/echo.Echo/Echo")
+        .header("content-type", "application/grpc")
+        .body(tonic::body::Body::empty())?;
+    service.call(request).await?;
+
+    let snapshot = snapshotter.snapshot().into_vec();
+    let overhead = histogram_values(&snapshot, "tonic_metrics.overhead");
+    assert_eq!(overhead.len(), 1);
+    assert!(overhead[0] > 0.0);
+
+    Ok(())
+}
+
+#[test]
+async fn not_ready_server_metrics() -> Result<(), Box<dyn std::error::Error>> {
+    let (snapshotter, _guard) = install_debug_recorder();
+
+    let mut service = ServerMetricsLayer::builder()
+        .with_not_ready(true)
+        .build()
+        .layer(SlowReadyService { ready: false });
+    future::poll_fn(|cx| Service::<http::Request<tonic::body::Body>>::poll_ready(&mut service, cx))
+        .await?;
+
+    let snapshot = snapshotter.snapshot().into_vec();
+    let not_ready = snapshot
+        .iter()
+        .find(|(key, ..)| key.key().name() == "rpc.server.not_ready")
+        .map(|(.., value)| value);
+    assert_eq!(not_ready, Some(&DebugValue::Counter(1)));
+
+    Ok(())
+}
+
+#[test]
+async fn metric_alias_unit_server_metrics() -> Result<(), Box<dyn std::error::Error>> {
+    let (snapshotter, _guard) = install_debug_recorder();
+
+    let mut service = ServerMetricsLayer::builder()
+        .duration_unit(DurationUnit::Seconds)
+        .with_unit_suffix(true)
+        .with_metric_alias_unit("rpc.server.duration", DurationUnit::Milliseconds)
+        .clock(StepClock {
+            start: Instant::now(),
+            step: Duration::from_millis(250),
+            reads: AtomicU32::new(0),
+        })
+        .build()
+        .layer(ContentLengthService);
+    let request = http::Request::builder()
+        .uri("/echo.Echo/Echo")
+        .header("content-type", "application/grpc")
+        .body(tonic::body::Body::empty())?;
+    service.call(request).await?;
+
+    let snapshot = snapshotter.snapshot().into_vec();
+    assert_eq!(
+        histogram_values(&snapshot, "rpc.server.duration_seconds"),
+        vec![0.25]
+    );
+    // The alias is suffixed with its own unit
+    assert_eq!(
+        histogram_values(&snapshot, "rpc.server.duration_milliseconds"),
+        vec![250.0]
+    );
+
+    Ok(())
+}
+
+#[test]
+async fn last_request_timestamp_server_metrics() -> Result<(), Box<dyn std::error::Error>> {
+    let (snapshotter, _guard) = install_debug_recorder();
+
+    let mut service = ServerMetricsLayer::builder()
+        .with_last_request_timestamp(true)
+        .build()
+        .layer(ContentLengthService);
+    let before = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs_f64();
+    let request = http::Request::builder()
+        .uri("/echo.Echo/Echo")
+        .header("content-type", "application/grpc")
+        .body(tonic::body::Body::empty())?;
+    service.call(request).await?;
+    let after = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs_f64();
+
+    let snapshot = snapshotter.snapshot().into_vec();
+    let (key, unit, _, value) = snapshot
+        .iter()
+        .find(|(key, ..)| key.key().name() == "rpc.server.last_request_timestamp")
+        .expect("timestamp gauge should be recorded");
+    let labels: Vec<_> = key.key().labels().map(|l| (l.key(), l.value())).collect();
+    assert_eq!(
+        labels,
+        vec![("rpc.method", "Echo"), ("rpc.service", "echo.Echo")]
+    );
+    assert_eq!(*unit, Some(Unit::Seconds));
+    let DebugValue::Gauge(timestamp) = value else {
+        panic!("expected a gauge, got {value:?}");
+    };
+    assert!((before..=after).contains(&timestamp.into_inner()));
+
+    Ok(())
+}
+
+#[test]
+async fn error_status_server_metrics() -> Result<(), Box<dyn std::error::Error>> {
+    let (snapshotter, _guard) = install_debug_recorder();
+
+    let (addr, handle) = spawn_server(Some(ServerMetricsLayer::new())).await?;
+
+    let request = tonic::Request::new(EchoRequest {
+        message: NOT_FOUND_MESSAGE.into(),
+    });
+    let status = EchoClient::connect(format!("http://{addr}"))
+        .await?
+        .echo(request)
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), tonic::Code::NotFound);
+
+    handle.abort();
+
+    let snapshot = snapshotter.snapshot();
+
+    println!("{:#?}", snapshot);
+    insta::with_settings!({filters => SNAPSHOT_FILTERS}, {
+        insta::assert_debug_snapshot!(snapshot);
+    });
+
+    Ok(())
+}
+
+#[test]
+async fn excluded_paths_server_metrics() -> Result<(), Box<dyn std::error::Error>> {
+    let (snapshotter, _guard) = install_debug_recorder();
+
+    let layer = ServerMetricsLayer::builder()
+        .with_excluded_paths(["/echo.Echo/Echo"])
+        .build();
+    let (addr, handle) = spawn_server(Some(layer)).await?;
+
+    send_request(&addr.to_string(), ClientMode::Plain)
+        .await
+        .unwrap();
+
+    handle.abort();
+
+    let snapshot = snapshotter.snapshot();
+
+    println!("{:#?}", snapshot);
+    assert!(snapshot.into_vec().is_empty());
+
+    Ok(())
+}
+
+#[test]
+async fn non_grpc_server_metrics() -> Result<(), Box<dyn std::error::Error>> {
+    let (snapshotter, _guard) = install_debug_recorder();
+
+    let mut service = ServerMetricsLayer::new().layer(HttpStatusService(http::StatusCode::OK));
+    let request = http::Request::builder()
+        .uri("/healthz")
+        .header("content-type", "text/plain")
+        .body(tonic::body::Body::empty())?;
+    service.call(request).await?;
+
+    assert!(snapshotter.snapshot().into_vec().is_empty());
+
+    let mut service = ServerMetricsLayer::builder()
+        .with_grpc_only(false)
+        .build()
+        .layer(HttpStatusService(http::StatusCode::OK));
+    let request = http::Request::builder()
+        .uri("/healthz")
+        .body(tonic::body::Body::empty())?;
+    service.call(request).await?;
+
+    assert!(!snapshotter.snapshot().into_vec().is_empty());
+
+    Ok(())
+}
+
+#[test]
+async fn filter_server_metrics() -> Result<(), Box<dyn std::error::Error>> {
+    let (snapshotter, _guard) = install_debug_recorder();
+
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let layer = ServerMetricsLayer::builder()
+        .with_filter({
+            let seen = seen.clone();
+            move |service, method| {
+                seen.lock()
+                    .unwrap()
+                    .push((service.to_string(), method.to_string()));
+                method != "Echo"
+            }
+        })
+        .build();
+    let (addr, handle) = spawn_server(Some(layer)).await?;
+
+    send_request(&addr.to_string(), ClientMode::Plain)
+        .await
+        .unwrap();
+
+    handle.abort();
+
+    assert_eq!(
+        *seen.lock().unwrap(),
+        vec![("echo.Echo".to_string(), "Echo".to_string())]
+    );
+    assert!(snapshotter.snapshot().into_vec().is_empty());
+
+    Ok(())
+}
+
+#[test]
+async fn sample_rate_server_metrics() -> Result<(), Box<dyn std::error::Error>> {
+    let (snapshotter, _guard) = install_debug_recorder();
+
+    let layer = ServerMetricsLayer::builder().with_sample_rate(0.0).build();
+    let (addr, handle) = spawn_server(Some(layer)).await?;
+
+    send_request(&addr.to_string(), ClientMode::Plain)
+        .await
+        .unwrap();
+
+    handle.abort();
+
+    assert!(snapshotter.snapshot().into_vec().is_empty());
+
+    Ok(())
+}
+
+#[test]
+async fn message_sizes_server_metrics() -> Result<(), Box<dyn std::error::Error>> {
+    let (snapshotter, _guard) = install_debug_recorder();
+
+    let layer = ServerMetricsLayer::builder()
+        .with_message_sizes(true)
+        .build();
+    let (addr, handle) = spawn_server(Some(layer)).await?;
+
+    send_request(&addr.to_string(), ClientMode::Plain)
+        .await
+        .unwrap();
+
+    handle.abort();
+
+    let snapshot = snapshotter.snapshot();
+
+    println!("{:#?}", snapshot);
+    insta::with_settings!({filters => SNAPSHOT_FILTERS}, {
+        insta::assert_debug_snapshot!(snapshot);
+    });
+
+    // The encoded `EchoRequest { message: "Hello" }` and its echo are 7 bytes each
+    let snapshot = snapshot.into_vec();
+    assert_eq!(
+        histogram_values(&snapshot, "rpc.server.request.size"),
+        vec![7.0]
+    );
+    assert_eq!(
+        histogram_values(&snapshot, "rpc.server.response.size"),
+        vec![7.0]
+    );
+
+    Ok(())
+}
+
+#[test]
+async fn compressed_message_sizes_server_metrics() -> Result<(), Box<dyn std::error::Error>> {
+    let (snapshotter, _guard) = install_debug_recorder();
+
+    let layer = ServerMetricsLayer::builder()
+        .with_message_sizes(true)
+        .build();
+    let (addr, handle) = spawn_server(Some(layer)).await?;
+
+    let request = tonic::Request::new(EchoRequest {
+        message: "Hello".into(),
+    });
+    EchoClient::connect(format!("http://{addr}"))
+        .await?
+        .send_compressed(CompressionEncoding::Gzip)
+        .echo(request)
+        .await?;
+
+    handle.abort();
+
+    // The request is compressed while the response is not
+    let snapshot = snapshotter.snapshot().into_vec();
+    assert!(histogram_values(&snapshot, "rpc.server.request.size").is_empty());
+    let compressed = histogram_values(&snapshot, "rpc.server.request.compressed_size");
+    assert_eq!(compressed.len(), 1);
+    assert!(compressed[0] > 0.0);
+    assert_eq!(
+        histogram_values(&snapshot, "rpc.server.response.size"),
+        vec![7.0]
+    );
+    assert!(histogram_values(&snapshot, "rpc.server.response.compressed_size").is_empty());
+
+    Ok(())
+}
+
+#[test]
+async fn request_encoding_label_server_metrics() -> Result<(), Box<dyn std::error::Error>> {
+    let (snapshotter, _guard) = install_debug_recorder();
+
+    let layer = ServerMetricsLayer::builder()
+        .with_request_encoding_label(true)
+        .build();
+    let (addr, handle) = spawn_server(Some(layer)).await?;
+
+    let request = tonic::Request::new(EchoRequest {
+        message: "Hello".into(),
+    });
+    EchoClient::connect(format!("http://{addr}"))
+        .await?
+        .send_compressed(CompressionEncoding::Gzip)
+        .echo(request)
+        .await?;
+
+    handle.abort();
+
+    let snapshot = snapshotter.snapshot().into_vec();
+    assert_eq!(
+        label_value(
+            &snapshot,
+            MetricKind::Counter,
+            "rpc.server.requests",
+            "rpc.grpc.request.encoding"
+        ),
+        Some("gzip".to_string())
+    );
+
+    Ok(())
+}
+
+#[test]
+async fn messages_per_rpc_server_metrics() -> Result<(), Box<dyn std::error::Error>> {
+    let (snapshotter, _guard) = install_debug_recorder();
+
+    let layer = ServerMetricsLayer::builder()
+        .with_messages_per_rpc(true)
+        .build();
+    let (addr, handle) = spawn_server(Some(layer)).await?;
+
+    send_request(&addr.to_string(), ClientMode::Plain)
+        .await
+        .unwrap();
+
+    handle.abort();
+
+    let snapshot = snapshotter.snapshot();
+
+    println!("{:#?}", snapshot);
+    insta::with_settings!({filters => SNAPSHOT_FILTERS}, {
+        insta::assert_debug_snapshot!(snapshot);
+    });
+
+    let snapshot = snapshot.into_vec();
+    assert_eq!(
+        histogram_values(&snapshot, "rpc.server.requests_per_rpc"),
+        vec![1.0]
+    );
+    assert_eq!(
+        histogram_values(&snapshot, "rpc.server.responses_per_rpc"),
+        vec![1.0]
+    );
+
+    Ok(())
+}
+
+#[test]
+async fn basic_client_metrics() -> Result<(), Box<dyn std::error::Error>> {
+    let (snapshotter, _guard) = install_debug_recorder();
+
+    let (addr, handle) = spawn_server(None).await?;
+
+    send_request(&addr.to_string(), ClientMode::Middleware)
+        .await
+        .unwrap();
+
+    handle.abort();
+
+    let snapshot = snapshotter.snapshot();
+
+    println!("{:#?}", snapshot);
+    insta::with_settings!({filters => SNAPSHOT_FILTERS}, {
+        insta::assert_debug_snapshot!(snapshot);
+    });
+
+    Ok(())
+}
+
+#[test]
+async fn basic_client_layer_metrics() -> Result<(), Box<dyn std::error::Error>> {
+    let (snapshotter, _guard) = install_debug_recorder();
+
+    let (addr, handle) = spawn_server(None).await?;
+
+    send_request(&addr.to_string(), ClientMode::Layer)
+        .await
+        .unwrap();
+
+    handle.abort();
+
+    let snapshot = snapshotter.snapshot();
+
+    println!("{:#?}", snapshot);
+    // The layer must produce exactly the same metrics as the middleware
+    insta::with_settings!({filters => SNAPSHOT_FILTERS}, {
+        insta::assert_debug_snapshot!("basic_client_metrics", snapshot);
+    });
+
+    Ok(())
+}
+
+#[test]
+async fn filter_client_metrics() -> Result<(), Box<dyn std::error::Error>> {
+    let (snapshotter, _guard) = install_debug_recorder();
+
+    let (addr, handle) = spawn_server(None).await?;
+
+    let channel = Channel::from_shared(format!("http://{addr}"))?
+        .connect()
+        .await?;
+    let metrics = ServiceBuilder::new()
+        .layer(
+            ClientMetricsLayer::builder()
+                .with_filter(|_, method| method != "Echo")
+                .build(),
+        )
+        .service(channel);
+    let request = tonic::Request::new(EchoRequest {
+        message: "Hello".into(),
+    });
+    EchoClient::new(metrics).echo(request).await?;
+
+    handle.abort();
+
+    assert!(snapshotter.snapshot().into_vec().is_empty());
+
+    Ok(())
+}
+
+#[test]
+async fn request_metadata_labels_client_metrics() -> Result<(), Box<dyn std::error::Error>> {
+    let (snapshotter, _guard) = install_debug_recorder();
+
+    let (addr, handle) = spawn_server(None).await?;
+
+    let channel = Channel::from_shared(format!("http://{addr}"))?
+        .connect()
+        .await?;
+    let metrics = ServiceBuilder::new()
+        .layer(
+            ClientMetricsLayer::builder()
+                .with_request_metadata_label("x-request-source", "request.source")
+                .with_request_metadata_label("x-region", "region")
+                .build(),
+        )
+        .service(channel);
+    let mut request = tonic::Request::new(EchoRequest {
+        message: "Hello".into(),
+    });
+    request
+        .metadata_mut()
+        .insert("x-request-source", "batch".parse()?);
+    EchoClient::new(metrics).echo(request).await?;
+
+    handle.abort();
+
+    let snapshot = snapshotter.snapshot().into_vec();
+    assert_eq!(
+        label_value(
+            &snapshot,
+            MetricKind::Counter,
+            "rpc.client.requests",
+            "request.source"
+        ),
+        Some("batch".to_string())
+    );
+    // Missing metadata omits the label
+    assert_eq!(
+        label_value(
+            &snapshot,
+            MetricKind::Counter,
+            "rpc.client.requests",
+            "region"
+        ),
+        None
+    );
+
+    Ok(())
+}
+
+#[test]
+async fn attempt_label_client_metrics() -> Result<(), Box<dyn std::error::Error>> {
+    let (snapshotter, _guard) = install_debug_recorder();
+
+    let (addr, handle) = spawn_server(None).await?;
+
+    let channel = Channel::from_shared(format!("http://{addr}"))?
+        .connect()
+        .await?;
+    let metrics = ServiceBuilder::new()
+        .layer(
+            ClientMetricsLayer::builder()
+                .with_attempt_label(true)
+                .build(),
+        )
+        .service(channel);
+    let mut client = EchoClient::new(metrics);
+    for attempt in [None, Some(RetryAttempt(2))] {
+        let mut request = tonic::Request::new(EchoRequest {
+            message: "Hello".into(),
+        });
+        if let Some(attempt) = attempt {
+            request.extensions_mut().insert(attempt);
+        }
+        client.echo(request).await?;
+    }
+
+    handle.abort();
+
+    let snapshot = snapshotter.snapshot().into_vec();
+    let mut attempts: Vec<_> = snapshot
+        .iter()
+        .filter(|(key, ..)| key.key().name() == "rpc.client.requests")
+        .map(|(key, ..)| {
+            key.key()
+                .labels()
+                .find(|label| label.key() == "rpc.attempt")
+                .map(|label| label.value().to_string())
+        })
+        .collect();
+    attempts.sort();
+    // Requests without the extension omit the label
+    assert_eq!(attempts, [None, Some("2".to_string())]);
+
+    Ok(())
+}
+
+#[test]
+async fn server_and_client_metrics() -> Result<(), Box<dyn std::error::Error>> {
+    let (snapshotter, _guard) = install_debug_recorder();
+
+    let (addr, handle) = spawn_server(Some(ServerMetricsLayer::new())).await?;
+
+    send_request(&addr.to_string(), ClientMode::Layer)
+        .await
+        .unwrap();
+
+    handle.abort();
+
+    // A proxy records both sides of the RPC into the same recorder, with the same labels
+    let snapshot = snapshotter.snapshot().into_vec();
+    assert_eq!(histogram_values(&snapshot, RPC_SERVER_DURATION).len(), 1);
+    assert_eq!(histogram_values(&snapshot, RPC_CLIENT_DURATION).len(), 1);
+    for label in ["rpc.service", "rpc.method", "rpc.grpc.status_code"] {
+        assert_eq!(
+            label_value(&snapshot, MetricKind::Histogram, RPC_SERVER_DURATION, label),
+            label_value(&snapshot, MetricKind::Histogram, RPC_CLIENT_DURATION, label),
+        );
+    }
+
+    Ok(())
+}
+
+#[test]
+async fn client_transport_error_metrics() -> Result<(), Box<dyn std::error::Error>> {
+    let (snapshotter, _guard) = install_debug_recorder();
+
+    // Bind and immediately release a port so nothing is listening on it
+    let addr = TcpIncoming::bind("[::1]:0".parse().unwrap())?.local_addr()?;
+    let addr = format!("http://{addr}");
+
+    let channel = Channel::from_shared(addr.clone())?.connect_lazy();
+    let metrics = ClientMetricsMiddleware::with_server_address(channel, Some(addr));
+    let request = tonic::Request::new(EchoRequest {
+        message: "Hello".into(),
+    });
+    let response = EchoClient::new(metrics).echo(request).await;
+    assert!(response.is_err());
+
+    let snapshot = snapshotter.snapshot();
+
+    println!("{:#?}", snapshot);
+    insta::with_settings!({filters => SNAPSHOT_FILTERS}, {
+        insta::assert_debug_snapshot!(snapshot);
+    });
+
+    Ok(())
+}
+
+#[test]
+async fn on_response_client_transport_error() -> Result<(), Box<dyn std::error::Error>> {
+    let (_snapshotter, _guard) = install_debug_recorder();
+
+    // Bind and immediately release a port so nothing is listening on it
+    let addr = TcpIncoming::bind("[::1]:0".parse().unwrap())?.local_addr()?;
+
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let channel = Channel::from_shared(format!("http://{addr}"))?.connect_lazy();
+    let metrics = ServiceBuilder::new()
+        .layer(
+            ClientMetricsLayer::builder()
+                .on_response({
+                    let seen = seen.clone();
+                    move |info| {
+                        seen.lock().unwrap().push((
+                            info.method().to_string(),
+                            info.grpc_status(),
+                            info.is_error(),
+                        ));
+                    }
+                })
+                .build(),
+        )
+        .service(channel);
+    let request = tonic::Request::new(EchoRequest {
+        message: "Hello".into(),
+    });
+    assert!(EchoClient::new(metrics).echo(request).await.is_err());
+
+    assert_eq!(
+        *seen.lock().unwrap(),
+        vec![("Echo".to_string(), None, true)]
+    );
+
+    Ok(())
+}
+
+#[test]
+async fn default_error_type_client_metrics() -> Result<(), Box<dyn std::error::Error>> {
+    let (snapshotter, _guard) = install_debug_recorder();
+
+    // Bind and immediately release a port so nothing is listening on it
+    let addr = TcpIncoming::bind("[::1]:0".parse().unwrap())?.local_addr()?;
+
+    let channel = Channel::from_shared(format!("http://{addr}"))?.connect_lazy();
+    let metrics = ServiceBuilder::new()
+        .layer(
+            ClientMetricsLayer::builder()
+                .with_default_error_type(|err| {
+                    Cow::Borrowed(if err.is::<tonic::transport::Error>() {
+                        "transport"
+                    } else {
+                        "other"
+                    })
+                })
+                .build(),
+        )
+        .service(channel);
+    let request = tonic::Request::new(EchoRequest {
+        message: "Hello".into(),
+    });
+    assert!(EchoClient::new(metrics).echo(request).await.is_err());
+
+    let snapshot = snapshotter.snapshot().into_vec();
+    assert_eq!(
+        label_value(
+            &snapshot,
+            MetricKind::Counter,
+            "rpc.client.requests",
+            "error.type"
+        ),
+        Some("transport".to_string())
+    );
+
+    Ok(())
+}
+
+#[cfg(feature = "exemplars")]
+#[test]
+async fn exemplars_server_metrics() -> Result<(), Box<dyn std::error::Error>> {
+    let (snapshotter, _guard) = install_debug_recorder();
+
+    let trace_ids = Arc::new(Mutex::new(Vec::new()));
+    let layer = ServerMetricsLayer::builder()
+        .on_response({
+            let trace_ids = trace_ids.clone();
+            move |info| {
+                trace_ids
+                    .lock()
+                    .unwrap()
+                    .push(info.trace_id().map(str::to_string))
+            }
+        })
+        .build();
+    let (addr, handle) = spawn_server(Some(layer)).await?;
+
+    let mut request = tonic::Request::new(EchoRequest {
+        message: "Hello".into(),
+    });
+    request.metadata_mut().insert(
+        "traceparent",
+        "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01".parse()?,
+    );
+    EchoClient::connect(format!("http://{addr}"))
+        .await?
+        .echo(request)
+        .await?;
+
+    handle.abort();
+
+    assert_eq!(
+        *trace_ids.lock().unwrap(),
+        vec![Some("4bf92f3577b34da6a3ce929d0e0e4736".to_string())]
+    );
+    // The trace id never becomes a label, which would create a series per trace
+    let snapshot = snapshotter.snapshot().into_vec();
+    assert_eq!(
+        label_value(
+            &snapshot,
+            MetricKind::Histogram,
+            "rpc.server.duration",
+            "trace_id"
+        ),
+        None
+    );
+
+    Ok(())
+}
+
+#[cfg(feature = "exemplars")]
+#[test]
+async fn exemplar_threshold_server_metrics() -> Result<(), Box<dyn std::error::Error>> {
+    let (snapshotter, _guard) = install_debug_recorder();
+
+    // Every RPC takes 250ms, only the layer with the lower threshold exposes the trace id
+    for (threshold, expected) in [(100, Some("4bf92f3577b34da6a3ce929d0e0e4736")), (500, None)] {
+        let trace_ids = Arc::new(Mutex::new(Vec::new()));
+        let mut service = ServerMetricsLayer::builder()
+            .clock(StepClock {
+                start: Instant::now(),
+                step: Duration::from_millis(250),
+                reads: AtomicU32::new(0),
+            })
+            .with_exemplar_threshold(Duration::from_millis(threshold))
+            .on_response({
+                let trace_ids = trace_ids.clone();
+                move |info| {
+                    trace_ids
+                        .lock()
+                        .unwrap()
+                        .push(info.trace_id().map(str::to_string))
+                }
+            })
+            .build()
+            .layer(ContentLengthService);
+        let request = http::Request::builder()
+            .uri("/echo.Echo/Echo")
+            .header("content-type", "application/grpc")
+            .header(
+                "traceparent",
+                "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            )
+            .body(tonic::body::Body::empty())?;
+        service.call(request).await?;
+
+        assert_eq!(
+            *trace_ids.lock().unwrap(),
+            vec![expected.map(str::to_string)]
+        );
+    }
+
+    let snapshot = snapshotter.snapshot().into_vec();
+    assert_eq!(
+        histogram_values(&snapshot, "rpc.server.duration"),
+        vec![250.0, 250.0]
+    );
+
+    Ok(())
+}
+
+/// Messages with this content make the echo service respond with `NOT_FOUND`.
+const NOT_FOUND_MESSAGE: &str = "not found";
+
+#[derive(Default)]
+pub struct MyEchoService;
+
+#[async_trait]
+impl Echo for MyEchoService {
+    async fn echo(&self, request: Request<EchoRequest>) -> Result<Response<EchoResponse>, Status> {
+        println!("Got a request from {:?}", request.remote_addr());
+
+        let message = request.into_inner().message;
+        if message == NOT_FOUND_MESSAGE {
+            return Err(Status::not_found("no such message"));
+        }
+
+        Ok(Response::new(EchoResponse { message }))
+    }
+}
+
+enum ClientMode {
+    Plain,
+    Middleware,
+    Layer,
+}
+
+async fn send_request(addr: &str, mode: ClientMode) -> Result<(), Box<dyn std::error::Error>> {
+    let addr = format!("http://{addr}");
+
+    let request = tonic::Request::new(EchoRequest {
+        message: "Hello".into(),
+    });
+
+    let response = match mode {
+        ClientMode::Plain => {
+            EchoClient::connect(addr)
+                .await
+                .unwrap()
+                .echo(request)
+                .await?
+        }
+        ClientMode::Middleware => {
+            let channel = Channel::from_shared(addr.to_string())?.connect().await?;
+            let metrics = ClientMetricsMiddleware::with_server_address(channel, Some(addr));
+            EchoClient::new(metrics).echo(request).await?
+        }
+        ClientMode::Layer => {
+            let channel = Channel::from_shared(addr.to_string())?.connect().await?;
+            let metrics = ServiceBuilder::new()
+                .layer(ClientMetricsLayer::with_server_address(Some(addr)))
+                .service(channel);
+            EchoClient::new(metrics).echo(request).await?
+        }
+    };
+
+    println!("RESPONSE={response:?}");
+
+    Ok(())
+}
+
+/// Names a test service `echo.Echo` so tonic routes every request for it there.
+#[derive(Clone)]
+struct Named<S>(S);
+
+impl<S> tonic::server::NamedService for Named<S> {
+    const NAME: &'static str = "echo.Echo";
+}
+
+impl<S, B> Service<http::Request<tonic::body::Body>> for Named<S>
+where
+    S: Service<http::Request<tonic::body::Body>, Response = http::Response<B>, Error = Infallible>,
+    S::Future: Send + 'static,
+    B: http_body::Body<Data = bytes::Bytes> + Send + 'static,
+    B::Error: Into<tower::BoxError>,
+{
+    type Response = http::Response<tonic::body::Body>;
+    type Error = Infallible;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.0.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: http::Request<tonic::body::Body>) -> Self::Future {
+        let future = self.0.call(req);
+        Box::pin(async move { Ok(future.await?.map(tonic::body::Body::new)) })
+    }
+}
+
+/// Spawns a test service behind `layer` on a random port, so its response body is driven by
+/// hyper, which stops polling as soon as the body reports its end.
+async fn spawn_service<S, B>(
+    layer: ServerMetricsLayer,
+    service: S,
+) -> Result<(SocketAddr, JoinHandle<()>), Box<dyn std::error::Error>>
+where
+    S: Service<http::Request<tonic::body::Body>, Response = http::Response<B>, Error = Infallible>
+        + Clone
+        + Send
+        + Sync
+        + 'static,
+    S::Future: Send + 'static,
+    B: http_body::Body<Data = bytes::Bytes> + Send + 'static,
+    B::Error: Into<tower::BoxError>,
+{
+    let incoming = TcpIncoming::bind("[::1]:0".parse().unwrap())?;
+    let addr = incoming.local_addr()?;
+
+    let handle = tokio::spawn(async move {
+        Server::builder()
+            .layer(layer)
+            .add_service(Named(service))
+            .serve_with_incoming(incoming)
+            .await
+            .unwrap();
+    });
+
+    Ok((addr, handle))
+}
+
+/// Sends a raw HTTP/2 request and reads the whole response, returning its status once the
+/// server had a chance to record it.
+async fn send_http_request(
+    addr: SocketAddr,
+    request: http::Request<tonic::body::Body>,
+) -> Result<http::StatusCode, Box<dyn std::error::Error>> {
+    let mut channel = Channel::from_shared(format!("http://{addr}"))?
+        .connect()
+        .await?;
+    future::poll_fn(|cx| channel.poll_ready(cx)).await?;
+    let response = channel.call(request).await?;
+    let status = response.status();
+
+    let mut body = pin!(response.into_body());
+    while future::poll_fn(|cx| http_body::Body::poll_frame(body.as_mut(), cx))
+        .await
+        .transpose()?
+        .is_some()
+    {}
+
+    // The server drops the response body after sending its last frame
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    Ok(status)
+}
+
+/// Spawns the echo server on a random port, optionally with the server metrics layer.
+async fn spawn_server(
+    layer: Option<ServerMetricsLayer>,
+) -> Result<(SocketAddr, JoinHandle<()>), Box<dyn std::error::Error>> {
+    let incoming = TcpIncoming::bind("[::1]:0".parse().unwrap())?;
+    let addr = incoming.local_addr()?;
+    let echo = EchoServer::new(MyEchoService).accept_compressed(CompressionEncoding::Gzip);
+
+    println!("GreeterServer listening on {addr}");
+
+    let handle = match layer {
+        Some(layer) => tokio::spawn(async move {
+            Server::builder()
+                .layer(layer)
+                .add_service(echo)
+                .serve_with_incoming(incoming)
+                .await
+                .unwrap();
+        }),
+        None => tokio::spawn(async move {
+            Server::builder()
+                .add_service(echo)
+                .serve_with_incoming(incoming)
+                .await
+                .unwrap();
+        }),
+    };
+
+    tokio::time::sleep(std::time::Duration::from_millis(150)).await;
+
+    Ok((addr, handle))
+}
+
+/// Returns the current value of the gauge with the given name.
+fn gauge_value(
+    snapshot: &[(CompositeKey, Option<Unit>, Option<SharedString>, DebugValue)],
+    name: &str,
+) -> Option<f64> {
+    snapshot
+        .iter()
+        .find(|(key, ..)| key.kind() == MetricKind::Gauge && key.key().name() == name)
+        .and_then(|(.., value)| match value {
+            DebugValue::Gauge(value) => Some(value.into_inner()),
+            _ => None,
+        })
+}
+
+/// Returns all values recorded for the histogram with the given name.
+fn histogram_values(
+    snapshot: &[(CompositeKey, Option<Unit>, Option<SharedString>, DebugValue)],
+    name: &str,
+) -> Vec<f64> {
+    snapshot
+        .iter()
+        .filter(|(key, ..)| key.kind() == MetricKind::Histogram && key.key().name() == name)
+        .flat_map(|(.., value)| match value {
+            DebugValue::Histogram(values) => values.iter().map(|v| v.into_inner()).collect(),
+            _ => Vec::new(),
+        })
+        .collect()
+}
+
+fn label_value(
+    snapshot: &[(CompositeKey, Option<Unit>, Option<SharedString>, DebugValue)],
+    kind: MetricKind,
+    name: &str,
+    label: &str,
+) -> Option<String> {
+    snapshot
+        .iter()
+        .find(|(key, ..)| key.kind() == kind && key.key().name() == name)
+        .and_then(|(key, ..)| {
+            key.key()
+                .labels()
+                .find(|l| l.key() == label)
+                .map(|l| l.value().to_string())
+        })
+}
+
+/// Installs a thread-local recorder so tests can run in parallel within one process.
+///
+/// Tests use the current thread runtime, so the spawned server and client tasks
+/// record into the same recorder as the test itself.
+fn install_debug_recorder() -> (Snapshotter, LocalRecorderGuard<'static>) {
+    let recorder: &'static DebuggingRecorder = Box::leak(Box::new(DebuggingRecorder::new()));
     let snapshotter = recorder.snapshotter();
-    recorder.install().unwrap();
-    snapshotter
+    let guard = metrics::set_default_local_recorder(recorder);
+    (snapshotter, guard)
 }